@@ -0,0 +1,392 @@
+// Contract events.
+//
+// Every event is published with a short symbol naming the event as its first topic, followed by
+// the address or asset indexers filter on. Event bodies are contract types so their layout is
+// part of the contract spec:
+//
+// | Topics                        | Body                       |
+// |-------------------------------|----------------------------|
+// | ("init")                      | InitializedEvent           |
+// | ("opened", user)              | PositionOpenedEvent        |
+// | ("closed", user)              | PositionClosedEvent        |
+// | ("increased", user)           | PositionIncreasedEvent     |
+// | ("decreased", user)           | PositionDecreasedEvent     |
+// | ("liquidated", user)          | PositionLiquidatedEvent    |
+// | ("adl", user)                 | PositionClosedEvent        |
+// | ("margin", user)              | CollateralChangedEvent     |
+// | ("tp_sl", user)               | TpSlEvent                  |
+// | ("triggered", user)           | TpSlTriggeredEvent         |
+// | ("trail_set", user)           | TrailingStop               |
+// | ("trail_del", user)           | u64 position ID            |
+// | ("trail_hit", user)           | TrailingStopTriggeredEvent |
+// | ("placed", user)              | Order                      |
+// | ("cancelled", user)           | u64 order ID               |
+// | ("executed", user)            | OrderExecutedEvent         |
+// | ("funding", asset)            | FundingRate                |
+// | ("breaker", asset)            | i128 rejected price        |
+// | ("brk_reset", asset)          | ()                         |
+// | ("bad_debt")                  | BadDebtEvent               |
+// | ("lp_dep", provider)          | LiquidityEvent             |
+// | ("lp_wd", provider)           | LiquidityEvent             |
+// | ("ins_dep", from)             | i128 amount                |
+// | ("acct_dep", user, token)     | i128 amount                |
+// | ("acct_wd", user, token)      | i128 amount                |
+// | ("seized", user)              | CollateralSeizedEvent      |
+// | ("config", "market")          | MarketConfig               |
+// | ("config", "collat")          | CollateralConfig           |
+// | ("config", "fees")            | FeeConfig                  |
+// | ("config", "oracle")          | Address                    |
+// | ("config", "prices")          | OracleConfig               |
+// | ("config", "max_util")        | i128 basis points          |
+// | ("config", "exec_fee")        | i128 collateral tokens     |
+// | ("admin", "proposed")         | Address of the new admin   |
+// | ("admin", "accepted")         | Address of the new admin   |
+use soroban_sdk::{contracttype, symbol_short, Address, Env, Symbol};
+
+use crate::reflector::Asset as ReflectorAsset;
+use crate::{
+    CollateralConfig, FeeConfig, FundingRate, MarketConfig, OracleConfig, Order, TrailingStop,
+};
+
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct InitializedEvent {
+    pub admin: Address,
+    pub treasury: Address,
+    pub collateral_token: Address,
+}
+
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PositionOpenedEvent {
+    pub position_id: u64,
+    pub asset: ReflectorAsset,
+    pub size: i128,
+    pub leverage: u32,
+    pub collateral: i128, // Net of the open fee
+    pub entry_price: i128,
+    pub fee: i128,
+}
+
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PositionClosedEvent {
+    pub position_id: u64,
+    pub asset: ReflectorAsset,
+    pub exit_price: i128,
+    pub realized_pnl: i128, // USD with fixed::USD_DECIMALS
+    pub funding: i128,      // USD paid (positive) or received (negative)
+    pub fee: i128,          // Close fee, or the bad debt haircut on auto-deleveraging
+    pub payout: i128,       // Collateral tokens returned to the user
+}
+
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PositionIncreasedEvent {
+    pub position_id: u64,
+    pub asset: ReflectorAsset,
+    pub size_delta: i128,
+    pub size: i128,        // Position size after the increase
+    pub collateral: i128,  // After extra collateral, settled funding and the open fee
+    pub entry_price: i128, // Averaged entry price
+    pub price: i128,
+    pub funding: i128, // USD settled: paid (positive) or received (negative)
+    pub fee: i128,
+}
+
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PositionDecreasedEvent {
+    pub position_id: u64,
+    pub asset: ReflectorAsset,
+    pub size_delta: i128, // Size closed
+    pub size: i128,       // Position size left open
+    pub collateral: i128, // Collateral left in the position
+    pub exit_price: i128,
+    pub realized_pnl: i128, // USD with fixed::USD_DECIMALS
+    pub funding: i128,      // USD paid (positive) or received (negative)
+    pub fee: i128,
+    pub payout: i128, // Collateral tokens returned to the user
+}
+
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CollateralChangedEvent {
+    pub position_id: u64,
+    pub asset: ReflectorAsset,
+    pub amount: i128,     // Collateral added (positive) or removed (negative)
+    pub collateral: i128, // Position collateral after the change
+    pub leverage: u32,    // Effective leverage after the change
+}
+
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TpSlEvent {
+    pub position_id: u64,
+    pub take_profit_price: Option<i128>,
+    pub stop_loss_price: Option<i128>,
+}
+
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TpSlTriggeredEvent {
+    pub position_id: u64,
+    pub keeper: Address,
+    pub price: i128,
+    pub take_profit: bool, // Whether the take profit (rather than the stop loss) was hit
+    pub keeper_fee: i128,  // Paid to the keeper out of the position payout
+}
+
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TrailingStopTriggeredEvent {
+    pub position_id: u64,
+    pub keeper: Address,
+    pub price: i128,
+    pub best_price: i128, // Best price seen before the retrace
+    pub keeper_fee: i128, // Paid to the keeper out of the position payout
+}
+
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OrderExecutedEvent {
+    pub order_id: u64,
+    pub position_id: u64, // Position the order was filled into
+    pub keeper: Address,
+    pub price: i128,
+    pub execution_fee: i128, // Paid to the keeper
+}
+
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PositionLiquidatedEvent {
+    pub position_id: u64,
+    pub asset: ReflectorAsset,
+    pub liquidator: Address,
+    pub price: i128,
+    pub liquidation_fee: i128,  // Paid to the liquidator
+    pub insurance_amount: i128, // Remaining collateral sent to the insurance fund
+}
+
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CollateralSeizedEvent {
+    pub token: Address,
+    pub liquidator: Address,
+    pub amount: i128, // Amount of `token` sold to the liquidator
+    pub repaid: i128, // Collateral tokens paid by the liquidator towards the account's debt
+}
+
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BadDebtEvent {
+    pub deficit: i128,     // Loss in excess of the position collateral
+    pub covered: i128,     // Paid by the insurance fund
+    pub outstanding: i128, // Total bad debt left after this event
+}
+
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LiquidityEvent {
+    pub amount: i128,
+    pub shares: i128,
+}
+
+pub fn initialized(env: &Env, event: InitializedEvent) {
+    env.events().publish((symbol_short!("init"),), event);
+}
+
+pub fn position_opened(env: &Env, user: &Address, event: PositionOpenedEvent) {
+    env.events()
+        .publish((symbol_short!("opened"), user.clone()), event);
+}
+
+pub fn position_closed(env: &Env, user: &Address, event: PositionClosedEvent) {
+    env.events()
+        .publish((symbol_short!("closed"), user.clone()), event);
+}
+
+pub fn position_increased(env: &Env, user: &Address, event: PositionIncreasedEvent) {
+    env.events()
+        .publish((symbol_short!("increased"), user.clone()), event);
+}
+
+pub fn position_decreased(env: &Env, user: &Address, event: PositionDecreasedEvent) {
+    env.events()
+        .publish((symbol_short!("decreased"), user.clone()), event);
+}
+
+pub fn position_liquidated(env: &Env, user: &Address, event: PositionLiquidatedEvent) {
+    env.events()
+        .publish((Symbol::new(env, "liquidated"), user.clone()), event);
+}
+
+pub fn position_deleveraged(env: &Env, user: &Address, event: PositionClosedEvent) {
+    env.events()
+        .publish((symbol_short!("adl"), user.clone()), event);
+}
+
+pub fn collateral_changed(env: &Env, user: &Address, event: CollateralChangedEvent) {
+    env.events()
+        .publish((symbol_short!("margin"), user.clone()), event);
+}
+
+pub fn tp_sl_set(env: &Env, user: &Address, event: TpSlEvent) {
+    env.events()
+        .publish((symbol_short!("tp_sl"), user.clone()), event);
+}
+
+pub fn tp_sl_triggered(env: &Env, user: &Address, event: TpSlTriggeredEvent) {
+    env.events()
+        .publish((symbol_short!("triggered"), user.clone()), event);
+}
+
+pub fn trailing_stop_set(env: &Env, user: &Address, trailing_stop: &TrailingStop) {
+    env.events().publish(
+        (symbol_short!("trail_set"), user.clone()),
+        trailing_stop.clone(),
+    );
+}
+
+pub fn trailing_stop_cancelled(env: &Env, user: &Address, position_id: u64) {
+    env.events()
+        .publish((symbol_short!("trail_del"), user.clone()), position_id);
+}
+
+pub fn trailing_stop_triggered(env: &Env, user: &Address, event: TrailingStopTriggeredEvent) {
+    env.events()
+        .publish((symbol_short!("trail_hit"), user.clone()), event);
+}
+
+pub fn order_placed(env: &Env, user: &Address, order: &Order) {
+    env.events()
+        .publish((symbol_short!("placed"), user.clone()), order.clone());
+}
+
+pub fn order_cancelled(env: &Env, user: &Address, order_id: u64) {
+    env.events()
+        .publish((symbol_short!("cancelled"), user.clone()), order_id);
+}
+
+pub fn order_executed(env: &Env, user: &Address, event: OrderExecutedEvent) {
+    env.events()
+        .publish((symbol_short!("executed"), user.clone()), event);
+}
+
+pub fn funding_updated(env: &Env, funding: &FundingRate) {
+    env.events().publish(
+        (symbol_short!("funding"), funding.asset.clone()),
+        funding.clone(),
+    );
+}
+
+pub fn circuit_breaker_tripped(env: &Env, asset: &ReflectorAsset, price: i128) {
+    env.events()
+        .publish((symbol_short!("breaker"), asset.clone()), price);
+}
+
+pub fn circuit_breaker_reset(env: &Env, asset: &ReflectorAsset) {
+    env.events()
+        .publish((symbol_short!("brk_reset"), asset.clone()), ());
+}
+
+pub fn bad_debt(env: &Env, event: BadDebtEvent) {
+    env.events().publish((symbol_short!("bad_debt"),), event);
+}
+
+pub fn liquidity_deposited(env: &Env, provider: &Address, event: LiquidityEvent) {
+    env.events()
+        .publish((symbol_short!("lp_dep"), provider.clone()), event);
+}
+
+pub fn liquidity_withdrawn(env: &Env, provider: &Address, event: LiquidityEvent) {
+    env.events()
+        .publish((symbol_short!("lp_wd"), provider.clone()), event);
+}
+
+pub fn insurance_deposited(env: &Env, from: &Address, amount: i128) {
+    env.events()
+        .publish((symbol_short!("ins_dep"), from.clone()), amount);
+}
+
+pub fn margin_deposited(env: &Env, user: &Address, token: &Address, amount: i128) {
+    env.events().publish(
+        (symbol_short!("acct_dep"), user.clone(), token.clone()),
+        amount,
+    );
+}
+
+pub fn margin_withdrawn(env: &Env, user: &Address, token: &Address, amount: i128) {
+    env.events().publish(
+        (symbol_short!("acct_wd"), user.clone(), token.clone()),
+        amount,
+    );
+}
+
+pub fn collateral_seized(env: &Env, user: &Address, event: CollateralSeizedEvent) {
+    env.events()
+        .publish((symbol_short!("seized"), user.clone()), event);
+}
+
+pub fn market_config_changed(env: &Env, config: &MarketConfig) {
+    env.events().publish(
+        (symbol_short!("config"), symbol_short!("market")),
+        config.clone(),
+    );
+}
+
+pub fn collateral_config_changed(env: &Env, config: &CollateralConfig) {
+    env.events().publish(
+        (symbol_short!("config"), symbol_short!("collat")),
+        config.clone(),
+    );
+}
+
+pub fn fee_config_changed(env: &Env, config: &FeeConfig) {
+    env.events().publish(
+        (symbol_short!("config"), symbol_short!("fees")),
+        config.clone(),
+    );
+}
+
+pub fn oracle_changed(env: &Env, oracle_address: &Address) {
+    env.events().publish(
+        (symbol_short!("config"), symbol_short!("oracle")),
+        oracle_address.clone(),
+    );
+}
+
+pub fn oracle_config_changed(env: &Env, config: &OracleConfig) {
+    env.events().publish(
+        (symbol_short!("config"), symbol_short!("prices")),
+        config.clone(),
+    );
+}
+
+pub fn max_utilization_changed(env: &Env, max_utilization: i128) {
+    env.events().publish(
+        (symbol_short!("config"), symbol_short!("max_util")),
+        max_utilization,
+    );
+}
+
+pub fn execution_fee_changed(env: &Env, execution_fee: i128) {
+    env.events().publish(
+        (symbol_short!("config"), symbol_short!("exec_fee")),
+        execution_fee,
+    );
+}
+
+pub fn admin_proposed(env: &Env, new_admin: &Address) {
+    env.events().publish(
+        (symbol_short!("admin"), symbol_short!("proposed")),
+        new_admin.clone(),
+    );
+}
+
+pub fn admin_changed(env: &Env, new_admin: &Address) {
+    env.events().publish(
+        (symbol_short!("admin"), symbol_short!("accepted")),
+        new_admin.clone(),
+    );
+}
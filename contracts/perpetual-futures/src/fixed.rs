@@ -0,0 +1,251 @@
+// Fixed-point arithmetic for prices, position sizes, collateral amounts and USD values.
+//
+// Every quantity carries its decimals explicitly so values from different sources (oracle prices,
+// token amounts, position sizes) are only combined through the conversions below. All operations
+// are overflow-checked and take a rounding direction, which callers choose in the protocol's favor
+// (margin requirements and losses round up, collateral value and profits round down).
+use crate::Error;
+
+// Position sizes are denominated in the traded asset with 7 decimals (1 BTC = 10_000_000)
+pub const SIZE_DECIMALS: u32 = 7;
+// Notional values, margins, PnL and funding are denominated in USD with 7 decimals
+pub const USD_DECIMALS: u32 = 7;
+// Oracle prices are rescaled to 14 decimals (Reflector's default) before they are aggregated
+pub const PRICE_DECIMALS: u32 = 14;
+// Denominator for values expressed in basis points
+pub const BPS_DENOMINATOR: i128 = 10_000;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Rounding {
+    Down, // Towards negative infinity
+    Up,   // Towards positive infinity
+}
+
+// Oracle price of one whole unit of an asset, in USD or in a quote asset that is itself valued in USD
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Price {
+    pub value: i128,
+    pub decimals: u32, // As reported by ReflectorClient::decimals, or PRICE_DECIMALS once rescaled
+    pub quote_value: i128, // USD price of one whole unit of the quote asset (1 for USD)
+    pub quote_decimals: u32,
+}
+
+// Signed position size (positive for long, negative for short) with SIZE_DECIMALS
+#[derive(Clone, Copy, Debug, Eq, PartialEq, PartialOrd, Ord)]
+pub struct Size(pub i128);
+
+// Amount of a collateral token in its own decimals
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Collateral {
+    pub amount: i128,
+    pub decimals: u32, // As reported by the token contract
+}
+
+// Signed USD value with USD_DECIMALS
+#[derive(Clone, Copy, Debug, Eq, PartialEq, PartialOrd, Ord)]
+pub struct Usd(pub i128);
+
+impl Price {
+    pub fn new(value: i128, decimals: u32) -> Self {
+        Price {
+            value,
+            decimals,
+            quote_value: 1,
+            quote_decimals: 0,
+        }
+    }
+
+    // This price taken as quoted in an asset whose own USD price is `quote_price`
+    pub fn in_quote(self, quote_price: Price) -> Self {
+        Price {
+            quote_value: quote_price.value,
+            quote_decimals: quote_price.decimals,
+            ..self
+        }
+    }
+
+    // Another price of the same asset, quoted the same way
+    pub fn with_value(self, value: i128) -> Self {
+        Price { value, ..self }
+    }
+
+    // USD value of an amount of the quote asset with USD_DECIMALS
+    fn quote_to_usd(self, amount: i128, rounding: Rounding) -> Result<i128, Error> {
+        mul_div(
+            amount,
+            self.quote_value,
+            pow10(self.quote_decimals)?,
+            rounding,
+        )
+    }
+
+    // The same price with a different number of decimals, rounded down when precision is dropped
+    pub fn rescale(self, decimals: u32) -> Result<Price, Error> {
+        let value = if decimals >= self.decimals {
+            self.value
+                .checked_mul(pow10(decimals - self.decimals)?)
+                .ok_or(Error::MathOverflow)?
+        } else {
+            mul_div(
+                self.value,
+                1,
+                pow10(self.decimals - decimals)?,
+                Rounding::Down,
+            )?
+        };
+        Ok(Price {
+            value,
+            decimals,
+            ..self
+        })
+    }
+}
+
+impl Size {
+    // Absolute USD value of the position at the given price
+    pub fn notional(self, price: Price, rounding: Rounding) -> Result<Usd, Error> {
+        let value = mul_rescale(
+            self.0.abs(),
+            price.value,
+            SIZE_DECIMALS + price.decimals,
+            USD_DECIMALS,
+            rounding,
+        )?;
+        Ok(Usd(price.quote_to_usd(value, rounding)?))
+    }
+
+    // Profit (positive) or loss (negative) of the position between two prices, realized in the
+    // quote asset and valued at the exit price's quote.
+    // Rounded down, so profits are never overstated and losses never understated.
+    pub fn pnl(self, entry_price: Price, exit_price: Price) -> Result<Usd, Error> {
+        if entry_price.decimals != exit_price.decimals {
+            return Err(Error::OracleError);
+        }
+        let delta = exit_price
+            .value
+            .checked_sub(entry_price.value)
+            .ok_or(Error::MathOverflow)?;
+        let value = mul_rescale(
+            self.0,
+            delta,
+            SIZE_DECIMALS + exit_price.decimals,
+            USD_DECIMALS,
+            Rounding::Down,
+        )?;
+        Ok(Usd(exit_price.quote_to_usd(value, Rounding::Down)?))
+    }
+}
+
+impl Collateral {
+    pub fn new(amount: i128, decimals: u32) -> Self {
+        Collateral { amount, decimals }
+    }
+
+    // USD value of the collateral at the given token price
+    pub fn value(self, price: Price, rounding: Rounding) -> Result<Usd, Error> {
+        let value = mul_rescale(
+            self.amount,
+            price.value,
+            self.decimals + price.decimals,
+            USD_DECIMALS,
+            rounding,
+        )?;
+        Ok(Usd(price.quote_to_usd(value, rounding)?))
+    }
+
+    // Token amount worth the given USD value at the given token price
+    pub fn from_value(
+        value: Usd,
+        decimals: u32,
+        price: Price,
+        rounding: Rounding,
+    ) -> Result<Self, Error> {
+        if price.value <= 0 {
+            return Err(Error::OracleError);
+        }
+        // amount = value * 10^(decimals + price.decimals - USD_DECIMALS) / price
+        let (numerator, denominator) = if decimals + price.decimals >= USD_DECIMALS {
+            (
+                pow10(decimals + price.decimals - USD_DECIMALS)?,
+                price.value,
+            )
+        } else {
+            let scale = pow10(USD_DECIMALS - decimals - price.decimals)?;
+            (
+                1,
+                price.value.checked_mul(scale).ok_or(Error::MathOverflow)?,
+            )
+        };
+        let amount = mul_div(value.0, numerator, denominator, rounding)?;
+        Ok(Collateral { amount, decimals })
+    }
+}
+
+impl Usd {
+    pub fn checked_add(self, other: Usd) -> Result<Usd, Error> {
+        Ok(Usd(self
+            .0
+            .checked_add(other.0)
+            .ok_or(Error::MathOverflow)?))
+    }
+
+    pub fn checked_sub(self, other: Usd) -> Result<Usd, Error> {
+        Ok(Usd(self
+            .0
+            .checked_sub(other.0)
+            .ok_or(Error::MathOverflow)?))
+    }
+
+    // Fraction of the value expressed in basis points
+    pub fn bps(self, bps: i128, rounding: Rounding) -> Result<Usd, Error> {
+        Ok(Usd(mul_div(self.0, bps, BPS_DENOMINATOR, rounding)?))
+    }
+
+    pub fn div(self, divisor: i128, rounding: Rounding) -> Result<Usd, Error> {
+        Ok(Usd(mul_div(self.0, 1, divisor, rounding)?))
+    }
+}
+
+// 10^exponent, failing instead of overflowing
+pub fn pow10(exponent: u32) -> Result<i128, Error> {
+    10i128.checked_pow(exponent).ok_or(Error::MathOverflow)
+}
+
+// a * b / denominator with an explicit rounding direction
+pub fn mul_div(a: i128, b: i128, denominator: i128, rounding: Rounding) -> Result<i128, Error> {
+    if denominator == 0 {
+        return Err(Error::MathOverflow);
+    }
+    let product = a.checked_mul(b).ok_or(Error::MathOverflow)?;
+    let quotient = product
+        .checked_div(denominator)
+        .ok_or(Error::MathOverflow)?;
+    let remainder = product % denominator;
+    if remainder == 0 {
+        return Ok(quotient);
+    }
+    // Truncation rounded towards zero; adjust when that is the wrong direction
+    let is_negative = (remainder < 0) != (denominator < 0);
+    match rounding {
+        Rounding::Down if is_negative => quotient.checked_sub(1).ok_or(Error::MathOverflow),
+        Rounding::Up if !is_negative => quotient.checked_add(1).ok_or(Error::MathOverflow),
+        _ => Ok(quotient),
+    }
+}
+
+// a * b, where the product carries `from_decimals`, rescaled to `to_decimals`
+fn mul_rescale(
+    a: i128,
+    b: i128,
+    from_decimals: u32,
+    to_decimals: u32,
+    rounding: Rounding,
+) -> Result<i128, Error> {
+    if from_decimals >= to_decimals {
+        mul_div(a, b, pow10(from_decimals - to_decimals)?, rounding)
+    } else {
+        a.checked_mul(b)
+            .and_then(|product| product.checked_mul(pow10(to_decimals - from_decimals).ok()?))
+            .ok_or(Error::MathOverflow)
+    }
+}
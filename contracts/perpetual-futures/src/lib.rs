@@ -1,16 +1,124 @@
 #![no_std]
 use soroban_sdk::{
-    contract, contracterror, contractimpl, contracttype, symbol_short, vec, Address, Env, Symbol,
-    Vec,
+    contract, contracterror, contractimpl, contracttype, token, vec, Address, Env, Map, Vec,
 };
 
 // Import Reflector oracle interface
 mod reflector;
 use reflector::{Asset as ReflectorAsset, ReflectorClient};
 
+mod fixed;
+use fixed::{Collateral, Price, Rounding, Size, Usd, PRICE_DECIMALS};
+
+pub mod events;
+use events::{
+    BadDebtEvent, CollateralChangedEvent, CollateralSeizedEvent, InitializedEvent, LiquidityEvent,
+    OrderExecutedEvent, PositionClosedEvent, PositionDecreasedEvent, PositionIncreasedEvent,
+    PositionLiquidatedEvent, PositionOpenedEvent, TpSlEvent, TpSlTriggeredEvent,
+    TrailingStopTriggeredEvent,
+};
+
+// Funding rates are fractions of notional scaled by 10^9; the cumulative funding index is in USD
+// per whole unit of size, scaled the same way
+const FUNDING_PRECISION: i128 = 1_000_000_000;
+
+// Share of the vault's USD value that net trader exposure may use unless set by the admin (80%)
+const DEFAULT_MAX_UTILIZATION: i128 = 8_000;
+
+// Oracle prices older than this many resolution periods are stale unless set by the admin
+const DEFAULT_MAX_PRICE_AGE_PERIODS: u32 = 2;
+
+// Collateral tokens escrowed with each limit order and paid to the keeper that executes it, unless
+// set by the admin (1 XLM)
+const DEFAULT_EXECUTION_FEE: i128 = 10_000_000;
+
+// Storage TTLs in ledgers (about 5 seconds each). Entries are extended back to the full TTL once
+// their remaining lifetime drops below the threshold.
+const DAY_IN_LEDGERS: u32 = 17_280;
+const INSTANCE_BUMP_AMOUNT: u32 = 30 * DAY_IN_LEDGERS;
+const INSTANCE_LIFETIME_THRESHOLD: u32 = INSTANCE_BUMP_AMOUNT - DAY_IN_LEDGERS;
+const POSITION_BUMP_AMOUNT: u32 = 30 * DAY_IN_LEDGERS;
+const POSITION_LIFETIME_THRESHOLD: u32 = POSITION_BUMP_AMOUNT - 7 * DAY_IN_LEDGERS;
+
 #[contract]
 pub struct PerpetualFutures;
 
+// Outcome of closing a position at a given price
+struct Settlement {
+    pnl: Usd,
+    funding: Usd,
+    collateral: i128, // Position collateral, or the margin account balance for cross-margin positions
+    final_collateral: i128, // Collateral tokens left; negative when the loss exceeded the collateral
+}
+
+// Health of a cross-margin account at current prices, in USD
+struct AccountHealth {
+    equity: Usd,             // Collateral value plus unrealized PnL less accrued funding
+    unrealized_profit: Usd,  // Sum of the positive unrealized PnL of the positions
+    initial_margin: Usd,     // Sum of position notionals divided by their leverage
+    maintenance_margin: Usd, // Sum of position maintenance margins
+}
+
+// Storage keys.
+//
+// Protocol configuration, per-market state and global accounting are bounded in size and live in
+// instance storage, which is extended on every state-changing call. Positions, per-user position
+// indexes, one-way net position IDs, margin accounts, LP share balances and trading volumes grow
+// with usage and live in persistent storage, one entry each, extended whenever they are touched
+// (or through bump_positions and bump_users).
+//
+// Limit orders are removed from storage once they are executed or cancelled, and trailing stops
+// once they are cancelled or their position is closed.
+//
+// Closed positions are dropped from their owner's index and are no longer extended, so they are
+// archived by the network once their TTL lapses. An archived position can still be restored with
+// a RestoreFootprint operation; the events published on close remain the permanent record.
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum DataKey {
+    // Instance storage
+    Initialized,
+    Admin,
+    PendingAdmin,
+    Treasury,
+    CollateralToken,
+    MinCollateral,
+    MaxLeverage,
+    MaintenanceMargin,
+    FundingInterval,
+    Oracle,
+    OracleConfig,
+    NextPositionId,
+    NextOrderId,
+    ExecutionFee,
+    TotalPositions,
+    Markets,
+    Market(ReflectorAsset),
+    CollateralTokens,
+    Collateral(Address),
+    Funding(ReflectorAsset),
+    OpenInterest(ReflectorAsset),
+    FeeConfig,
+    Vault,
+    MaxUtilization,
+    InsuranceFund,
+    BadDebt,
+    OracleSource(Address), // Decimals and resolution discovered for an oracle contract
+    CircuitBreaker(ReflectorAsset), // Set while a market's circuit breaker is tripped
+    LastPrice(ReflectorAsset, QuoteAsset), // Last oracle price accepted for an asset, in USD or a quote asset
+    SettledPnl(ReflectorAsset), // Trader PnL of a market's open interest at its last traded price
+    // Persistent storage
+    Position(u64),
+    UserPositions(Address),
+    NetPosition(Address, ReflectorAsset), // Position ID of a user's net position in a one-way market
+    LpShares(Address),
+    MarginAccount(Address),
+    Order(u64),
+    UserOrders(Address),
+    TrailingStop(u64),      // Keyed by position ID
+    TradingVolume(Address), // USD notional a user has traded, for fee tiers
+}
+
 #[contracterror]
 #[derive(Copy, Clone, Debug, Eq, PartialEq, PartialOrd, Ord)]
 #[repr(u32)]
@@ -28,6 +136,17 @@ pub enum Error {
     MaintenanceMarginNotMet = 11,
     OracleError = 12,
     InvalidAmount = 13,
+    MarketAlreadyExists = 14,
+    MathOverflow = 15,
+    NoBadDebt = 16,
+    PositionNotProfitable = 17,
+    OrderNotFound = 18,
+    OrderExpired = 19,
+    PriceNotReached = 20,
+    InvalidTriggerPrice = 21,
+    IsolatedOnly = 22,
+    PriceDeviation = 23,
+    MarketHasOpenInterest = 24,
 }
 
 #[contracttype]
@@ -36,68 +155,227 @@ pub struct Position {
     pub id: u64,
     pub user: Address,
     pub asset: ReflectorAsset,
-    pub size: i128, // Positive for long, negative for short
-    pub collateral: i128,
-    pub entry_price: i128,
+    pub size: i128, // Positive for long, negative for short, with fixed::SIZE_DECIMALS
+    pub collateral: i128, // Collateral token amount in the token's own decimals; 0 for cross margin
+    pub entry_price: i128, // Price in the market's quote asset with fixed::PRICE_DECIMALS
     pub leverage: u32,
     pub timestamp: u64,
     pub is_open: bool,
+    pub funding_index: i128, // Market cumulative funding index when the position was opened
+    pub take_profit_price: Option<i128>, // Close once the price moves this far in the position's favor
+    pub stop_loss_price: Option<i128>,   // Close once the price moves this far against the position
+    pub cross_margin: bool, // Backed by the owner's margin account instead of its own collateral
+}
+
+// Cross-margin account: a basket of collateral backing all of a user's cross-margin positions.
+// Losses settle against the balance of the collateral token; once that runs out the account owes
+// the vault, up to the haircut value of its other collateral.
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MarginAccount {
+    pub balance: i128, // Collateral token amount; negative when the account owes the vault
+    pub collateral: Map<Address, i128>, // Amounts of other whitelisted collateral tokens
+    pub positions: Vec<u64>, // Open cross-margin positions
+}
+
+// Token accepted as margin. Its value backing margin is its oracle value less the haircut.
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CollateralConfig {
+    pub token: Address,
+    pub asset: ReflectorAsset, // Asset the token is priced as on the global oracle
+    pub haircut: i128,         // In basis points of the token's value
+    pub is_active: bool,       // Inactive tokens can be withdrawn but not deposited
+}
+
+// How orders on a market combine with a user's existing positions. The mode of a market can only
+// be changed while it has no open interest.
+#[contracttype]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PositionMode {
+    Hedge,  // Every order opens a separate position, so longs and shorts can be held together
+    OneWay, // A single net position per user; opposite orders reduce, close or flip it
+}
+
+// Limit order to open (or, in one-way mode, net against) a position once the oracle price reaches
+// the limit price: at or below it for longs, at or above it for shorts
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Order {
+    pub id: u64,
+    pub user: Address,
+    pub asset: ReflectorAsset,
+    pub size: i128, // Positive for long, negative for short, with fixed::SIZE_DECIMALS
+    pub leverage: u32,
+    pub collateral: i128,    // Escrowed collateral token amount
+    pub limit_price: i128,   // Price in the market's quote asset with fixed::PRICE_DECIMALS
+    pub expiry: u64,         // Ledger timestamp after which the order can no longer be executed
+    pub execution_fee: i128, // Escrowed keeper reward in collateral tokens
+}
+
+// How far the price may retrace from the best price seen before a trailing stop closes the position
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum TrailingDistance {
+    Percent(i128),  // In basis points of the best price
+    Absolute(i128), // Price difference in the market's quote asset with fixed::PRICE_DECIMALS
+}
+
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TrailingStop {
+    pub position_id: u64,
+    pub distance: TrailingDistance,
+    pub best_price: i128, // Highest price seen for a long, lowest for a short
 }
 
 #[contracttype]
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct MarketConfig {
     pub asset: ReflectorAsset,
+    pub quote_asset: QuoteAsset,
     pub min_collateral: i128,
     pub max_leverage: u32,
     pub maintenance_margin: i128,   // In basis points (e.g., 500 = 5%)
     pub funding_rate_interval: u64, // In seconds
-    pub oracle_address: Address,
+    pub max_funding_rate: i128, // Rate per interval at full long/short imbalance, scaled by FUNDING_PRECISION
+    pub liquidation_fee: i128,  // Keeper reward in basis points of the liquidated notional
+    pub open_fee: i128,         // Trading fee in basis points of notional, charged at open
+    pub close_fee: i128,        // Trading fee in basis points of notional, charged at close
+    pub position_mode: PositionMode,
+    pub oracle_sources: Vec<Address>, // Reflector contracts whose prices are aggregated into their median
+    pub oracle_quorum: u32,           // Sources that must report a fresh price for it to be used
     pub is_active: bool,
+    pub mark_price_records: u32, // Oracle records averaged into the mark price (0 = spot price)
+    pub mark_price_fallback: MarkPriceFallback,
+}
+
+// What a market's price is quoted in
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum QuoteAsset {
+    Usd,                   // The oracles' base asset
+    Asset(ReflectorAsset), // Another asset, priced through the oracles' cross prices
+}
+
+// What to use as the mark price when the oracle can not provide a time-weighted average
+#[contracttype]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MarkPriceFallback {
+    Spot,   // The latest price
+    Reject, // Nothing: actions that need the mark price fail until the average is available
+}
+
+// Price precision and update period of an oracle contract, queried when it is configured
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OracleSource {
+    pub decimals: u32,   // As reported by ReflectorClient::decimals
+    pub resolution: u32, // Seconds between price updates, as reported by ReflectorClient::resolution
+}
+
+// Bounds every oracle price is checked against before it is used
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OracleConfig {
+    pub max_age_periods: u32, // Maximum price age, in resolution periods of the reporting oracle
+    pub max_deviation: i128, // Maximum move from the last accepted price, in basis points (0 = unbounded)
 }
 
 #[contracttype]
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct FundingRate {
     pub asset: ReflectorAsset,
-    pub rate: i128, // In basis points (e.g., 100 = 1%)
-    pub timestamp: u64,
+    pub rate: i128, // Last applied rate per interval, scaled by FUNDING_PRECISION (positive = longs pay shorts)
+    pub cumulative_index: i128, // Sum of applied rates times the mark price of one unit of size, scaled by FUNDING_PRECISION
+    pub timestamp: u64,         // Start of the interval that has not been accrued yet
+}
+
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OpenInterest {
+    pub long_size: i128,
+    pub short_size: i128,
+    pub long_notional: i128,  // USD value of open longs at their entry prices
+    pub short_notional: i128, // USD value of open shorts at their entry prices
+}
+
+// How trading fees are split, in basis points summing to 10_000, and the volume tiers that
+// discount them
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FeeConfig {
+    pub treasury_share: i128,
+    pub insurance_share: i128,
+    pub vault_share: i128,
+    pub tiers: Vec<FeeTier>, // Sorted by ascending min_volume
+}
+
+// Fee discount for users whose traded notional has reached min_volume
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FeeTier {
+    pub min_volume: i128, // USD notional, with 7 decimals
+    pub discount: i128,   // Basis points of the open and close fees waived
+}
+
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Vault {
+    pub balance: i128,      // Collateral tokens owned by liquidity providers
+    pub total_shares: i128, // Outstanding LP shares
 }
 
 #[contractimpl]
 impl PerpetualFutures {
-    // Initialize the contract
+    // Initialize the contract. `collateral` is the SEP-41 token (e.g. the XLM Stellar Asset
+    // Contract) positions are settled in and the asset it is priced as on the oracle; it is always
+    // valued without a haircut. The built-in Reflector oracle must quote that asset.
+    #[allow(clippy::too_many_arguments)]
     pub fn initialize(
         env: Env,
         admin: Address,
         treasury: Address,
+        collateral: CollateralConfig,
         min_collateral: i128,
         max_leverage: u32,
         maintenance_margin: i128,
         funding_rate_interval: u64,
     ) -> Result<(), Error> {
-        if env.storage().instance().has(&symbol_short!("init")) {
+        if env.storage().instance().has(&DataKey::Initialized) {
             return Err(Error::AlreadyInitialized);
         }
+        // The admin signs, so a front-runner cannot initialize the contract with their own config
+        admin.require_auth();
+        if collateral.haircut != 0 || !collateral.is_active {
+            return Err(Error::InvalidAmount);
+        }
+        let collateral_token = collateral.token.clone();
 
+        env.storage().instance().set(&DataKey::Admin, &admin);
+        env.storage().instance().set(&DataKey::Treasury, &treasury);
         env.storage()
             .instance()
-            .set(&symbol_short!("admin"), &admin);
+            .set(&DataKey::CollateralToken, &collateral_token);
         env.storage()
             .instance()
-            .set(&symbol_short!("treasury"), &treasury);
+            .set(&DataKey::Collateral(collateral_token.clone()), &collateral);
+        env.storage().instance().set(
+            &DataKey::CollateralTokens,
+            &vec![&env, collateral_token.clone()],
+        );
         env.storage()
             .instance()
-            .set(&symbol_short!("min_coll"), &min_collateral);
+            .set(&DataKey::MinCollateral, &min_collateral);
         env.storage()
             .instance()
-            .set(&symbol_short!("max_lev"), &max_leverage);
+            .set(&DataKey::MaxLeverage, &max_leverage);
         env.storage()
             .instance()
-            .set(&symbol_short!("marg"), &maintenance_margin);
+            .set(&DataKey::MaintenanceMargin, &maintenance_margin);
         env.storage()
             .instance()
-            .set(&symbol_short!("fund_int"), &funding_rate_interval);
+            .set(&DataKey::FundingInterval, &funding_rate_interval);
 
         // Set the Reflector oracle address for external CEX & DEX price feeds
         // Testnet: CCYOZJCOPG34LLQQ7N24YXBM7LL62R7ONMZ3G6WZAAYPB5OYKOMJRN63
@@ -106,11 +384,24 @@ impl PerpetualFutures {
             &env,
             "CCYOZJCOPG34LLQQ7N24YXBM7LL62R7ONMZ3G6WZAAYPB5OYKOMJRN63",
         );
+        // Like set_oracle, cache its precision and update period and check it quotes the
+        // settlement token, so prices can be read right away
+        Self::discover_oracle_source(&env, &oracle_address, &vec![&env, collateral.asset])?;
         env.storage()
             .instance()
-            .set(&symbol_short!("oracle"), &oracle_address);
+            .set(&DataKey::Oracle, &oracle_address);
 
-        env.storage().instance().set(&symbol_short!("init"), &true);
+        env.storage().instance().set(&DataKey::Initialized, &true);
+        Self::extend_instance_ttl(&env);
+
+        events::initialized(
+            &env,
+            InitializedEvent {
+                admin,
+                treasury,
+                collateral_token,
+            },
+        );
 
         Ok(())
     }
@@ -119,256 +410,2347 @@ impl PerpetualFutures {
     pub fn open_position(
         env: Env,
         user: Address,
-        asset_symbol: Symbol, // Asset symbol like "BTC", "ETH", "SOL"
+        asset: ReflectorAsset, // Market's base asset, e.g. Other("BTC") or a Stellar token
         size: i128,
         leverage: u32,
         collateral: i128,
     ) -> Result<u64, Error> {
         user.require_auth();
-        // Validate inputs
-        if collateral < Self::get_min_collateral(env.clone()) {
-            return Err(Error::InsufficientCollateral);
+        Self::extend_instance_ttl(&env);
+
+        // Only listed, active markets can be traded
+        let market = Self::get_market(env.clone(), asset.clone())?;
+        if !Self::is_tradable(&env, &market) {
+            return Err(Error::InvalidAsset);
         }
-        if leverage > Self::get_max_leverage(env.clone()) || leverage == 0 {
+
+        // Validate inputs
+        if leverage > market.max_leverage || leverage == 0 {
             return Err(Error::InvalidLeverage);
         }
         if size == 0 {
             return Err(Error::PositionTooSmall);
         }
 
-        // Create ReflectorAsset::Other for external assets
-        let asset = ReflectorAsset::Other(asset_symbol);
+        Self::execute_market_order(&env, &user, &market, size, leverage, collateral, false)
+    }
 
-        // Get current price from oracle
-        let oracle_address = Self::get_oracle_address(&env)?;
-        let reflector_client = ReflectorClient::new(&env, &oracle_address);
+    // Close a position
+    pub fn close_position(env: Env, user: Address, position_id: u64) -> Result<(), Error> {
+        user.require_auth();
+        Self::extend_instance_ttl(&env);
+        // Ensure only the position owner can close it
+        let position = Self::get_open_position(&env, &user, position_id)?;
 
-        let price_data = reflector_client
-            .lastprice(&asset)
-            .ok_or(Error::OracleError)?;
+        // Closing stays allowed while a market is paused
+        let market = Self::get_market(env.clone(), position.asset.clone())?;
+        Self::execute_close(&env, &user, &market, position, None)?;
+        Ok(())
+    }
 
-        // Check if price is stale (older than 6 minutes to allow for oracle resolution)
-        let current_timestamp = env.ledger().timestamp();
-        if current_timestamp - price_data.timestamp >= 360 {
-            return Err(Error::PriceStale);
+    // Add collateral to an open position, lowering its effective leverage
+    pub fn add_collateral(
+        env: Env,
+        user: Address,
+        position_id: u64,
+        amount: i128,
+    ) -> Result<(), Error> {
+        user.require_auth();
+        Self::extend_instance_ttl(&env);
+        if amount <= 0 {
+            return Err(Error::InvalidAmount);
         }
 
-        let entry_price = price_data.price;
+        let mut position = Self::get_isolated_position(&env, &user, position_id)?;
+        let market = Self::get_market(env.clone(), position.asset.clone())?;
 
-        // Calculate position value and required margin in USD
-        // Size is in 14 decimals (10^14), entry_price is in 14 decimals
-        // So position_value = size * entry_price / 10^28 gives USD value
-        let position_value = size.abs() * entry_price / 10_000_000_000_000_000_000_000_000_000i128; // 14 + 14 decimals = 10^28
-        let required_margin = position_value / leverage as i128;
+        Self::token_client(&env).transfer(&user, &env.current_contract_address(), &amount);
 
-        // Get XLM price in USD to convert collateral from XLM to USD
-        let xlm_asset = ReflectorAsset::Other(Symbol::new(&env, "XLM"));
-        let xlm_price_data = reflector_client
-            .lastprice(&xlm_asset)
-            .ok_or(Error::OracleError)?;
+        let previous_collateral = position.collateral;
+        position.collateral = position
+            .collateral
+            .checked_add(amount)
+            .ok_or(Error::MathOverflow)?;
 
-        // Convert XLM collateral to USD value for comparison
-        // collateral is in 7 decimals, xlm_price is in 14 decimals
-        // USD value = (collateral / 10^7) * (xlm_price / 10^14)
-        // = collateral * xlm_price / (10^7 * 10^14) = collateral * xlm_price / 10^21
-        let collateral_usd_value = (collateral * xlm_price_data.price) / 1_000_000_000_000_000_000_000i128;
+        // Adding collateral only makes the position safer, so it does not need a usable price.
+        // Without one, the leverage is scaled down by the added collateral instead.
+        position.leverage = match Self::get_market_price(&env, &market)
+            .and_then(|price| Self::get_effective_leverage(&env, &position, price))
+        {
+            Ok(leverage) => leverage,
+            Err(_) => {
+                let leverage = fixed::mul_div(
+                    position.leverage as i128,
+                    previous_collateral,
+                    position.collateral,
+                    Rounding::Up,
+                )?;
+                u32::try_from(leverage.max(1)).map_err(|_| Error::MathOverflow)?
+            }
+        };
+        Self::save_position(&env, &position);
 
-        if collateral_usd_value < required_margin {
-            return Err(Error::InsufficientCollateral);
+        events::collateral_changed(
+            &env,
+            &user,
+            CollateralChangedEvent {
+                position_id,
+                asset: position.asset,
+                amount,
+                collateral: position.collateral,
+                leverage: position.leverage,
+            },
+        );
+
+        Ok(())
+    }
+
+    // Withdraw collateral from an open position. The remaining collateral, less any unrealized
+    // profit, must still cover the initial margin at the market's maximum leverage and keep the
+    // position above its maintenance margin.
+    pub fn remove_collateral(
+        env: Env,
+        user: Address,
+        position_id: u64,
+        amount: i128,
+    ) -> Result<(), Error> {
+        user.require_auth();
+        Self::extend_instance_ttl(&env);
+
+        let mut position = Self::get_isolated_position(&env, &user, position_id)?;
+        if amount <= 0 || amount >= position.collateral {
+            return Err(Error::InvalidAmount);
         }
 
-        // Generate position ID
-        let position_id = Self::get_next_position_id(&env);
+        let market = Self::get_market(env.clone(), position.asset.clone())?;
+        let current_price = Self::get_market_price(&env, &market)?;
 
-        let position = Position {
-            id: position_id,
-            user: user.clone(),
-            asset,
-            size,
-            leverage,
-            collateral,
-            entry_price,
-            timestamp: current_timestamp,
-            is_open: true,
-        };
+        // Bring funding up to date so equity reflects everything the position owes
+        Self::accrue_funding(&env, &market)?;
+        position.collateral -= amount;
 
-        // Store position
-        env.storage()
-            .instance()
-            .set(&(symbol_short!("pos"), position_id), &position);
+        Self::check_initial_margin(&env, &position, &market, current_price)?;
+        let mark_price = Self::get_mark_price(&env, &market)?;
+        if Self::is_position_liquidatable(&env, &position, &market, mark_price)? {
+            return Err(Error::MaintenanceMarginNotMet);
+        }
 
-        // Update user positions
-        let mut user_positions =
-            Self::get_user_positions(env.clone(), user.clone());
-        user_positions.push_back(position_id);
-        env.storage().instance().set(
-            &(symbol_short!("user_pos"), user.clone()),
-            &user_positions,
-        );
+        position.leverage = Self::get_effective_leverage(&env, &position, current_price)?;
+        Self::save_position(&env, &position);
 
-        // Update total open positions
-        let total_positions = Self::get_total_positions(env.clone()) + 1;
-        env.storage()
-            .instance()
-            .set(&symbol_short!("total_pos"), &total_positions);
+        Self::token_client(&env).transfer(&env.current_contract_address(), &user, &amount);
 
-        Ok(position_id)
+        events::collateral_changed(
+            &env,
+            &user,
+            CollateralChangedEvent {
+                position_id,
+                asset: position.asset,
+                amount: -amount,
+                collateral: position.collateral,
+                leverage: position.leverage,
+            },
+        );
+
+        Ok(())
     }
 
-    // Close a position
-    pub fn close_position(env: Env, user: Address, position_id: u64) -> Result<(), Error> {
+    // Add `size_delta` (in the position's direction) to an open position. Funding accrued so far is
+    // settled against the collateral and the entry price becomes the size-weighted average of the
+    // old entry price and the current price.
+    pub fn increase_position(
+        env: Env,
+        user: Address,
+        position_id: u64,
+        size_delta: i128,
+        extra_collateral: i128,
+    ) -> Result<(), Error> {
         user.require_auth();
-        let position: Position = env
-            .storage()
-            .instance()
-            .get(&(symbol_short!("pos"), position_id))
-            .ok_or(Error::PositionNotFound)?;
+        Self::extend_instance_ttl(&env);
 
-        if !position.is_open {
-            return Err(Error::PositionNotFound);
+        let position = Self::get_isolated_position(&env, &user, position_id)?;
+        let market = Self::get_market(env.clone(), position.asset.clone())?;
+        if !Self::is_tradable(&env, &market) {
+            return Err(Error::InvalidAsset);
         }
 
-        // Ensure only the position owner can close it
-        if position.user != user {
-            return Err(Error::Unauthorized);
-        }
+        Self::execute_increase(
+            &env,
+            &user,
+            &market,
+            position,
+            size_delta,
+            extra_collateral,
+            false,
+        )
+    }
+
+    // Close `size_delta` of an open position. PnL and funding are realized on the closed size and a
+    // proportional share of the collateral is released with it; losses beyond that share are taken
+    // from the remaining collateral. Use close_position to close the whole position.
+    pub fn decrease_position(
+        env: Env,
+        user: Address,
+        position_id: u64,
+        size_delta: i128,
+    ) -> Result<(), Error> {
+        user.require_auth();
+        Self::extend_instance_ttl(&env);
 
-        // Get current price from oracle
-        let oracle_address = Self::get_oracle_address(&env)?;
-        let reflector_client = ReflectorClient::new(&env, &oracle_address);
+        let position = Self::get_isolated_position(&env, &user, position_id)?;
 
-        let price_data = reflector_client
-            .lastprice(&position.asset)
-            .ok_or(Error::OracleError)?;
+        // Decreasing stays allowed while a market is paused
+        let market = Self::get_market(env.clone(), position.asset.clone())?;
 
-        let current_price = price_data.price;
+        Self::execute_decrease(&env, &user, &market, position, size_delta)
+    }
 
-        // Calculate PnL
-        let pnl = if position.size > 0 {
-            // Long position
-            (current_price - position.entry_price) * position.size / 10_000_000_000_000_000i128
+    // Deposit a whitelisted collateral token into the caller's cross-margin account. Deposits of
+    // the protocol collateral token repay any debt the account owes first.
+    pub fn deposit_margin(
+        env: Env,
+        user: Address,
+        token: Address,
+        amount: i128,
+    ) -> Result<(), Error> {
+        user.require_auth();
+        Self::extend_instance_ttl(&env);
+        if amount <= 0 {
+            return Err(Error::InvalidAmount);
+        }
+        let config = Self::get_collateral_config(env.clone(), token.clone())?;
+        if !config.is_active {
+            return Err(Error::InvalidAsset);
+        }
+
+        token::Client::new(&env, &token).transfer(&user, &env.current_contract_address(), &amount);
+
+        let mut account = Self::get_margin_account(env.clone(), user.clone());
+        if token == Self::get_collateral_token(env.clone()) {
+            account.balance = account
+                .balance
+                .checked_add(amount)
+                .ok_or(Error::MathOverflow)?;
         } else {
-            // Short position
-            (position.entry_price - current_price) * position.size.abs()
-                / 10_000_000_000_000_000i128
-        };
+            let held = account.collateral.get(token.clone()).unwrap_or(0);
+            account.collateral.set(
+                token.clone(),
+                held.checked_add(amount).ok_or(Error::MathOverflow)?,
+            );
+        }
+        Self::save_margin_account(&env, &user, &account);
 
-        // Calculate final collateral
-        let _final_collateral = position.collateral + pnl;
+        events::margin_deposited(&env, &user, &token, amount);
 
-        // Update position
-        let mut updated_position = position;
-        updated_position.is_open = false;
-        env.storage()
-            .instance()
-            .set(&(symbol_short!("pos"), position_id), &updated_position);
+        Ok(())
+    }
 
-        // Update total open positions
-        let total_positions = Self::get_total_positions(env.clone()) - 1;
-        env.storage()
-            .instance()
-            .set(&symbol_short!("total_pos"), &total_positions);
+    // Withdraw collateral from a cross-margin account. The rest of the account, less any
+    // unrealized profit, must still cover the initial margin of its positions.
+    pub fn withdraw_margin(
+        env: Env,
+        user: Address,
+        token: Address,
+        amount: i128,
+    ) -> Result<(), Error> {
+        user.require_auth();
+        Self::extend_instance_ttl(&env);
 
-        // Transfer final collateral back to user (this would need to be implemented with actual token transfers)
+        let mut account = Self::get_margin_account(env.clone(), user.clone());
+        if token == Self::get_collateral_token(env.clone()) {
+            if amount <= 0 || amount > account.balance {
+                return Err(Error::InvalidAmount);
+            }
+            account.balance -= amount;
+        } else {
+            let held = account.collateral.get(token.clone()).unwrap_or(0);
+            if amount <= 0 || amount > held {
+                return Err(Error::InvalidAmount);
+            }
+            if held == amount {
+                account.collateral.remove(token.clone());
+            } else {
+                account.collateral.set(token.clone(), held - amount);
+            }
+        }
+        Self::save_margin_account(&env, &user, &account);
+        Self::check_account_margin(&env, &user)?;
+
+        token::Client::new(&env, &token).transfer(&env.current_contract_address(), &user, &amount);
+
+        events::margin_withdrawn(&env, &user, &token, amount);
 
         Ok(())
     }
 
-    // Liquidate an undercollateralized position
-    pub fn liquidate_position(env: Env, position_id: u64) -> Result<(), Error> {
-        let position: Position = env
-            .storage()
-            .instance()
-            .get(&(symbol_short!("pos"), position_id))
-            .ok_or(Error::PositionNotFound)?;
+    // Open a position backed by the caller's cross-margin account. The open fee is charged to the
+    // account, which must cover the initial margin of all its positions afterwards. Only available
+    // on hedge mode markets.
+    pub fn open_cross_position(
+        env: Env,
+        user: Address,
+        asset: ReflectorAsset,
+        size: i128,
+        leverage: u32,
+    ) -> Result<u64, Error> {
+        user.require_auth();
+        Self::extend_instance_ttl(&env);
 
-        if !position.is_open {
-            return Err(Error::PositionNotFound);
+        let market = Self::get_market(env.clone(), asset.clone())?;
+        if !Self::is_tradable(&env, &market) {
+            return Err(Error::InvalidAsset);
+        }
+        if market.position_mode == PositionMode::OneWay {
+            return Err(Error::IsolatedOnly);
         }
 
-        // Check if position needs liquidation
-        if !Self::is_position_liquidatable(&env, &position)? {
-            return Err(Error::MaintenanceMarginNotMet);
+        // Validate inputs
+        if leverage > market.max_leverage || leverage == 0 {
+            return Err(Error::InvalidLeverage);
+        }
+        if size == 0 {
+            return Err(Error::PositionTooSmall);
         }
 
-        // Close position at current price (similar to close_position but without PnL calculation)
-        let mut updated_position = position;
-        updated_position.is_open = false;
-        env.storage()
-            .instance()
-            .set(&(symbol_short!("pos"), position_id), &updated_position);
+        let price = Self::get_market_price(&env, &market)?;
 
-        // Update total open positions
-        let total_positions = Self::get_total_positions(env.clone()) - 1;
-        env.storage()
-            .instance()
-            .set(&symbol_short!("total_pos"), &total_positions);
+        // The open fee comes out of the account balance
+        let notional = Size(size).notional(price, Rounding::Up)?;
+        let open_fee = Self::get_fee_amount(&env, &user, notional, market.open_fee)?;
+        Self::record_volume(&env, &user, notional)?;
+        let mut account = Self::get_margin_account(env.clone(), user.clone());
+        if open_fee > account.balance {
+            return Err(Error::InsufficientCollateral);
+        }
+        account.balance -= open_fee;
+        Self::save_margin_account(&env, &user, &account);
+        Self::distribute_fee(&env, open_fee)?;
 
-        // Transfer remaining collateral to treasury (this would need to be implemented with actual token transfers)
+        let position_id = Self::create_position(
+            &env, &user, &market, size, leverage, 0, price, open_fee, true,
+        )?;
+        Self::check_account_margin(&env, &user)?;
 
-        Ok(())
+        Ok(position_id)
     }
 
-    // Get position details
-    pub fn get_position(env: Env, position_id: u64) -> Result<Position, Error> {
-        env.storage()
-            .instance()
-            .get(&(symbol_short!("pos"), position_id))
-            .ok_or(Error::PositionNotFound)
-    }
+    // Liquidate a cross-margin account whose equity has fallen below the maintenance margin of its
+    // positions, or which owes the vault without any open positions. Positions are closed largest
+    // loss first until the account is healthy again; the liquidator is paid each closed position's
+    // liquidation fee out of the account. Any debt left is then repaid by the liquidator in
+    // exchange for the account's other collateral at its haircut value. Returns the IDs of the
+    // closed positions.
+    pub fn liquidate_account(
+        env: Env,
+        liquidator: Address,
+        user: Address,
+    ) -> Result<Vec<u64>, Error> {
+        liquidator.require_auth();
+        Self::extend_instance_ttl(&env);
 
-    // Get user positions
-    pub fn get_user_positions(env: Env, user: Address) -> Vec<u64> {
-        env.storage()
-            .instance()
-            .get(&(symbol_short!("user_pos"), user))
-            .unwrap_or_else(|| vec![&env])
-    }
+        let account = Self::get_margin_account(env.clone(), user.clone());
+        let health = Self::get_account_health(&env, &user)?;
+        let in_debt = account.positions.is_empty() && account.balance < 0;
+        if health.equity >= health.maintenance_margin && !in_debt {
+            return Err(Error::MaintenanceMarginNotMet);
+        }
 
-    // Get total open positions count
-    pub fn get_total_positions(env: Env) -> u64 {
-        env.storage()
-            .instance()
-            .get(&symbol_short!("total_pos"))
-            .unwrap_or(0)
+        let mut closed = vec![&env];
+        loop {
+            // Pick the position with the largest unrealized loss, net of funding
+            let mut worst: Option<(Position, MarketConfig, Price, Usd)> = None;
+            for position_id in Self::get_margin_account(env.clone(), user.clone())
+                .positions
+                .iter()
+            {
+                let position = Self::get_position(env.clone(), position_id)?;
+                let market = Self::get_market(env.clone(), position.asset.clone())?;
+                Self::accrue_funding(&env, &market)?;
+                let price = Self::get_market_price(&env, &market)?;
+                let pnl = Self::get_unrealized_pnl(&env, &position, price)?;
+                if worst
+                    .as_ref()
+                    .is_none_or(|(_, _, _, worst_pnl)| pnl < *worst_pnl)
+                {
+                    worst = Some((position, market, price, pnl));
+                }
+            }
+            let Some((position, market, price, _)) = worst else {
+                break;
+            };
+
+            // Keeper reward is a fraction of the notional
+            let fee_value = Size(position.size)
+                .notional(price, Rounding::Down)?
+                .bps(market.liquidation_fee, Rounding::Down)?;
+            let fee_amount = Self::usd_to_collateral(&env, fee_value)?;
+
+            let position_id = position.id;
+            let asset = position.asset.clone();
+            let settlement = Self::settle_position(&env, position, &market, price)?;
+            let remaining_collateral = settlement.final_collateral.max(0);
+            let liquidation_fee = fee_amount.min(remaining_collateral);
+            if liquidation_fee > 0 {
+                Self::token_client(&env).transfer(
+                    &env.current_contract_address(),
+                    &liquidator,
+                    &liquidation_fee,
+                );
+            }
+            Self::pay_out(&env, &user, true, remaining_collateral - liquidation_fee);
+            closed.push_back(position_id);
+
+            events::position_liquidated(
+                &env,
+                &user,
+                PositionLiquidatedEvent {
+                    position_id,
+                    asset,
+                    liquidator: liquidator.clone(),
+                    price: price.value,
+                    liquidation_fee,
+                    insurance_amount: 0,
+                },
+            );
+
+            let health = Self::get_account_health(&env, &user)?;
+            if health.equity >= health.maintenance_margin {
+                break;
+            }
+        }
+        Self::seize_collateral(&env, &liquidator, &user)?;
+        Self::check_vault_solvency(&env)?;
+
+        Ok(closed)
     }
 
-    // Get contract admin
-    pub fn get_admin(env: Env) -> Address {
+    // Get a user's cross-margin account
+    pub fn get_margin_account(env: Env, user: Address) -> MarginAccount {
         env.storage()
-            .instance()
-            .get(&symbol_short!("admin"))
-            .unwrap()
+            .persistent()
+            .get(&DataKey::MarginAccount(user))
+            .unwrap_or_else(|| MarginAccount {
+                balance: 0,
+                collateral: Map::new(&env),
+                positions: vec![&env],
+            })
     }
 
-    // Get minimum collateral requirement
-    pub fn get_min_collateral(env: Env) -> i128 {
-        env.storage()
-            .instance()
-            .get(&symbol_short!("min_coll"))
-            .unwrap()
+    // Get the equity of a cross-margin account in USD: haircut collateral value plus unrealized
+    // PnL less accrued funding of its positions
+    pub fn get_account_equity(env: Env, user: Address) -> Result<i128, Error> {
+        Ok(Self::get_account_health(&env, &user)?.equity.0)
     }
 
-    // Get maximum leverage
-    pub fn get_max_leverage(env: Env) -> u32 {
-        env.storage()
+    // Set or clear the take-profit and stop-loss prices (with fixed::PRICE_DECIMALS) of an open
+    // position. Both must be on the correct side of the current price: above it for a long's take
+    // profit and below it for its stop loss, and the reverse for a short.
+    pub fn set_tp_sl(
+        env: Env,
+        user: Address,
+        position_id: u64,
+        take_profit_price: Option<i128>,
+        stop_loss_price: Option<i128>,
+    ) -> Result<(), Error> {
+        user.require_auth();
+        Self::extend_instance_ttl(&env);
+
+        let mut position = Self::get_open_position(&env, &user, position_id)?;
+        let market = Self::get_market(env.clone(), position.asset.clone())?;
+        let current_price = Self::get_market_price(&env, &market)?;
+
+        let is_long = position.size > 0;
+        if let Some(price) = take_profit_price {
+            if price <= 0 || (price > current_price.value) != is_long {
+                return Err(Error::InvalidTriggerPrice);
+            }
+        }
+        if let Some(price) = stop_loss_price {
+            if price <= 0 || (price < current_price.value) != is_long {
+                return Err(Error::InvalidTriggerPrice);
+            }
+        }
+
+        position.take_profit_price = take_profit_price;
+        position.stop_loss_price = stop_loss_price;
+        Self::save_position(&env, &position);
+
+        events::tp_sl_set(
+            &env,
+            &user,
+            TpSlEvent {
+                position_id,
+                take_profit_price,
+                stop_loss_price,
+            },
+        );
+
+        Ok(())
+    }
+
+    // Close a position whose take-profit or stop-loss price has been reached, settling it like
+    // close_position; callable by any keeper, who is paid the execution fee out of the payout
+    pub fn trigger_tp_sl(env: Env, keeper: Address, position_id: u64) -> Result<(), Error> {
+        keeper.require_auth();
+        Self::extend_instance_ttl(&env);
+
+        let position = Self::get_position(env.clone(), position_id)?;
+        if !position.is_open {
+            return Err(Error::PositionNotFound);
+        }
+
+        let market = Self::get_market(env.clone(), position.asset.clone())?;
+        let current_price = Self::get_market_price(&env, &market)?;
+
+        let price = current_price.value;
+        let (take_profit_hit, stop_loss_hit) = if position.size > 0 {
+            (
+                position.take_profit_price.is_some_and(|tp| price >= tp),
+                position.stop_loss_price.is_some_and(|sl| price <= sl),
+            )
+        } else {
+            (
+                position.take_profit_price.is_some_and(|tp| price <= tp),
+                position.stop_loss_price.is_some_and(|sl| price >= sl),
+            )
+        };
+        if !take_profit_hit && !stop_loss_hit {
+            return Err(Error::PriceNotReached);
+        }
+
+        let user = position.user.clone();
+        let keeper_fee = Self::execute_close(&env, &user, &market, position, Some(&keeper))?;
+
+        events::tp_sl_triggered(
+            &env,
+            &user,
+            TpSlTriggeredEvent {
+                position_id,
+                keeper,
+                price,
+                take_profit: take_profit_hit,
+                keeper_fee,
+            },
+        );
+
+        Ok(())
+    }
+
+    // Attach a trailing stop to an open position, replacing any existing one. The best price seen
+    // starts at the current price and is updated by poke_trailing_stop.
+    pub fn set_trailing_stop(
+        env: Env,
+        user: Address,
+        position_id: u64,
+        distance: TrailingDistance,
+    ) -> Result<(), Error> {
+        user.require_auth();
+        Self::extend_instance_ttl(&env);
+
+        let valid = match distance {
+            TrailingDistance::Percent(bps) => bps > 0 && bps < fixed::BPS_DENOMINATOR,
+            TrailingDistance::Absolute(amount) => amount > 0,
+        };
+        if !valid {
+            return Err(Error::InvalidAmount);
+        }
+
+        let position = Self::get_open_position(&env, &user, position_id)?;
+        let market = Self::get_market(env.clone(), position.asset.clone())?;
+        let current_price = Self::get_market_price(&env, &market)?;
+
+        let trailing_stop = TrailingStop {
+            position_id,
+            distance,
+            best_price: current_price.value,
+        };
+        Self::save_trailing_stop(&env, &trailing_stop);
+
+        events::trailing_stop_set(&env, &user, &trailing_stop);
+
+        Ok(())
+    }
+
+    // Remove the trailing stop of a position
+    pub fn cancel_trailing_stop(env: Env, user: Address, position_id: u64) -> Result<(), Error> {
+        user.require_auth();
+        Self::extend_instance_ttl(&env);
+
+        Self::get_open_position(&env, &user, position_id)?;
+        let key = DataKey::TrailingStop(position_id);
+        if !env.storage().persistent().has(&key) {
+            return Err(Error::OrderNotFound);
+        }
+        env.storage().persistent().remove(&key);
+
+        events::trailing_stop_cancelled(&env, &user, position_id);
+
+        Ok(())
+    }
+
+    // Update a trailing stop with the latest oracle price: a better price moves the best price
+    // seen, and a retrace beyond the trailing distance closes the position like close_position.
+    // Callable by any keeper, who is paid the execution fee out of the payout when the stop is hit.
+    // Returns whether the position was closed.
+    pub fn poke_trailing_stop(env: Env, keeper: Address, position_id: u64) -> Result<bool, Error> {
+        keeper.require_auth();
+        Self::extend_instance_ttl(&env);
+
+        let mut trailing_stop = Self::get_trailing_stop(env.clone(), position_id)?;
+        let position = Self::get_position(env.clone(), position_id)?;
+        if !position.is_open {
+            return Err(Error::PositionNotFound);
+        }
+
+        let market = Self::get_market(env.clone(), position.asset.clone())?;
+        let current_price = Self::get_market_price(&env, &market)?;
+
+        let price = current_price.value;
+        let is_long = position.size > 0;
+        if (is_long && price > trailing_stop.best_price)
+            || (!is_long && price < trailing_stop.best_price)
+        {
+            trailing_stop.best_price = price;
+        }
+
+        // The stop trails the best price by the configured distance
+        let best_price = trailing_stop.best_price;
+        let distance = match trailing_stop.distance {
+            TrailingDistance::Percent(bps) => {
+                fixed::mul_div(best_price, bps, fixed::BPS_DENOMINATOR, Rounding::Down)?
+            }
+            TrailingDistance::Absolute(amount) => amount,
+        };
+        let hit = if is_long {
+            price <= best_price - distance
+        } else {
+            price >= best_price + distance
+        };
+        if !hit {
+            Self::save_trailing_stop(&env, &trailing_stop);
+            return Ok(false);
+        }
+
+        let user = position.user.clone();
+        let keeper_fee = Self::execute_close(&env, &user, &market, position, Some(&keeper))?;
+
+        events::trailing_stop_triggered(
+            &env,
+            &user,
+            TrailingStopTriggeredEvent {
+                position_id,
+                keeper,
+                price,
+                best_price,
+                keeper_fee,
+            },
+        );
+
+        Ok(true)
+    }
+
+    // Get the trailing stop of a position
+    pub fn get_trailing_stop(env: Env, position_id: u64) -> Result<TrailingStop, Error> {
+        env.storage()
+            .persistent()
+            .get(&DataKey::TrailingStop(position_id))
+            .ok_or(Error::OrderNotFound)
+    }
+
+    // Place a limit order, escrowing its collateral and the keeper execution fee. It is filled by
+    // execute_order once the oracle price reaches `limit_price` (with fixed::PRICE_DECIMALS),
+    // until `expiry`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn place_limit_order(
+        env: Env,
+        user: Address,
+        asset: ReflectorAsset,
+        size: i128,
+        leverage: u32,
+        collateral: i128,
+        limit_price: i128,
+        expiry: u64,
+    ) -> Result<u64, Error> {
+        user.require_auth();
+        Self::extend_instance_ttl(&env);
+
+        let market = Self::get_market(env.clone(), asset.clone())?;
+        if !Self::is_tradable(&env, &market) {
+            return Err(Error::InvalidAsset);
+        }
+
+        // Validate inputs
+        if collateral < market.min_collateral {
+            return Err(Error::InsufficientCollateral);
+        }
+        if leverage > market.max_leverage || leverage == 0 {
+            return Err(Error::InvalidLeverage);
+        }
+        if size == 0 {
+            return Err(Error::PositionTooSmall);
+        }
+        if limit_price <= 0 {
+            return Err(Error::InvalidAmount);
+        }
+        if expiry <= env.ledger().timestamp() {
+            return Err(Error::OrderExpired);
+        }
+
+        let execution_fee = Self::get_execution_fee(env.clone());
+        Self::token_client(&env).transfer(
+            &user,
+            &env.current_contract_address(),
+            &collateral
+                .checked_add(execution_fee)
+                .ok_or(Error::MathOverflow)?,
+        );
+
+        let order_id = Self::get_next_order_id(&env);
+
+        let order = Order {
+            id: order_id,
+            user: user.clone(),
+            asset,
+            size,
+            leverage,
+            collateral,
+            limit_price,
+            expiry,
+            execution_fee,
+        };
+        let key = DataKey::Order(order_id);
+        env.storage().persistent().set(&key, &order);
+        Self::extend_persistent_ttl(&env, &key);
+
+        let mut user_orders = Self::get_user_orders(env.clone(), user.clone());
+        user_orders.push_back(order_id);
+        Self::save_user_orders(&env, &user, &user_orders);
+
+        events::order_placed(&env, &user, &order);
+
+        Ok(order_id)
+    }
+
+    // Cancel a limit order and refund its escrowed collateral and execution fee
+    pub fn cancel_order(env: Env, user: Address, order_id: u64) -> Result<(), Error> {
+        user.require_auth();
+        Self::extend_instance_ttl(&env);
+
+        let order = Self::get_order(env.clone(), order_id)?;
+        if order.user != user {
+            return Err(Error::Unauthorized);
+        }
+        Self::remove_order(&env, &order);
+
+        Self::token_client(&env).transfer(
+            &env.current_contract_address(),
+            &user,
+            &(order.collateral + order.execution_fee),
+        );
+
+        events::order_cancelled(&env, &user, order_id);
+
+        Ok(())
+    }
+
+    // Fill a limit order whose limit price has been reached; callable by any keeper, who is paid
+    // the order's execution fee. Returns the ID of the position the order was filled into.
+    pub fn execute_order(env: Env, keeper: Address, order_id: u64) -> Result<u64, Error> {
+        keeper.require_auth();
+        Self::extend_instance_ttl(&env);
+
+        let order = Self::get_order(env.clone(), order_id)?;
+        if env.ledger().timestamp() > order.expiry {
+            return Err(Error::OrderExpired);
+        }
+
+        let market = Self::get_market(env.clone(), order.asset.clone())?;
+        if !Self::is_tradable(&env, &market) {
+            return Err(Error::InvalidAsset);
+        }
+
+        // Longs fill at or below the limit price, shorts at or above it
+        let price = Self::get_market_price(&env, &market)?;
+        let reached = if order.size > 0 {
+            price.value <= order.limit_price
+        } else {
+            price.value >= order.limit_price
+        };
+        if !reached {
+            return Err(Error::PriceNotReached);
+        }
+
+        Self::remove_order(&env, &order);
+        let position_id = Self::execute_market_order(
+            &env,
+            &order.user,
+            &market,
+            order.size,
+            order.leverage,
+            order.collateral,
+            true,
+        )?;
+
+        if order.execution_fee > 0 {
+            Self::token_client(&env).transfer(
+                &env.current_contract_address(),
+                &keeper,
+                &order.execution_fee,
+            );
+        }
+
+        events::order_executed(
+            &env,
+            &order.user,
+            OrderExecutedEvent {
+                order_id,
+                position_id,
+                keeper,
+                price: price.value,
+                execution_fee: order.execution_fee,
+            },
+        );
+
+        Ok(position_id)
+    }
+
+    // Liquidate an undercollateralized position. The liquidator (keeper) is paid the market's
+    // liquidation fee out of the remaining collateral and the rest goes to the insurance fund.
+    pub fn liquidate_position(
+        env: Env,
+        liquidator: Address,
+        position_id: u64,
+    ) -> Result<(), Error> {
+        liquidator.require_auth();
+        Self::extend_instance_ttl(&env);
+        let position = Self::get_position(env.clone(), position_id)?;
+
+        if !position.is_open {
+            return Err(Error::PositionNotFound);
+        }
+
+        // Cross-margin positions are liquidated with their account
+        if position.cross_margin {
+            return Err(Error::IsolatedOnly);
+        }
+
+        // Check if position needs liquidation
+        let market = Self::get_market(env.clone(), position.asset.clone())?;
+        Self::accrue_funding(&env, &market)?;
+        let current_price = Self::get_market_price(&env, &market)?;
+        let mark_price = Self::get_mark_price(&env, &market)?;
+        if !Self::is_position_liquidatable(&env, &position, &market, mark_price)? {
+            return Err(Error::MaintenanceMarginNotMet);
+        }
+
+        // Keeper reward is a fraction of the notional
+        let fee_value = Size(position.size)
+            .notional(current_price, Rounding::Down)?
+            .bps(market.liquidation_fee, Rounding::Down)?;
+        let fee_amount = Self::usd_to_collateral(&env, fee_value)?;
+
+        // Settle PnL and funding against the collateral at the current price
+        let user = position.user.clone();
+        let asset = position.asset.clone();
+        let settlement = Self::settle_position(&env, position, &market, current_price)?;
+
+        // The reward is capped by what is left of the collateral
+        let remaining_collateral = settlement.final_collateral.max(0);
+        let liquidation_fee = fee_amount.min(remaining_collateral);
+        let insurance_amount = remaining_collateral - liquidation_fee;
+
+        // Pay the keeper and keep the remainder in the contract as insurance
+        if liquidation_fee > 0 {
+            Self::token_client(&env).transfer(
+                &env.current_contract_address(),
+                &liquidator,
+                &liquidation_fee,
+            );
+        }
+        Self::credit_insurance_fund(&env, insurance_amount);
+
+        events::position_liquidated(
+            &env,
+            &user,
+            PositionLiquidatedEvent {
+                position_id,
+                asset,
+                liquidator,
+                price: current_price.value,
+                liquidation_fee,
+                insurance_amount,
+            },
+        );
+
+        Ok(())
+    }
+
+    // Close a profitable position against outstanding bad debt once the insurance fund is exhausted.
+    // The position's profit is reduced by up to the outstanding bad debt.
+    pub fn auto_deleverage(env: Env, position_id: u64) -> Result<i128, Error> {
+        Self::require_admin(&env)?;
+        Self::extend_instance_ttl(&env);
+
+        let bad_debt = Self::get_bad_debt(env.clone());
+        if bad_debt == 0 {
+            return Err(Error::NoBadDebt);
+        }
+
+        let position = Self::get_position(env.clone(), position_id)?;
+        if !position.is_open {
+            return Err(Error::PositionNotFound);
+        }
+
+        let market = Self::get_market(env.clone(), position.asset.clone())?;
+        let current_price = Self::get_market_price(&env, &market)?;
+
+        let user = position.user.clone();
+        let asset = position.asset.clone();
+        let cross_margin = position.cross_margin;
+        let settlement = Self::settle_position(&env, position, &market, current_price)?;
+        let final_collateral = settlement.final_collateral;
+        let profit = final_collateral - settlement.collateral;
+        if profit <= 0 {
+            return Err(Error::PositionNotProfitable);
+        }
+
+        // Haircut the profit to pay down the bad debt, returning it to the vault that absorbed the loss
+        let haircut = profit.min(bad_debt);
+        env.storage()
+            .instance()
+            .set(&DataKey::BadDebt, &(bad_debt - haircut));
+        Self::update_vault_balance(&env, haircut);
+        Self::check_vault_solvency(&env)?;
+
+        let payout = final_collateral - haircut;
+        Self::pay_out(&env, &user, cross_margin, payout);
+
+        events::position_deleveraged(
+            &env,
+            &user,
+            PositionClosedEvent {
+                position_id,
+                asset,
+                exit_price: current_price.value,
+                realized_pnl: settlement.pnl.0,
+                funding: settlement.funding.0,
+                fee: haircut,
+                payout,
+            },
+        );
+
+        Ok(haircut)
+    }
+
+    // Deposit collateral tokens into the liquidity vault in exchange for LP shares
+    pub fn deposit_liquidity(env: Env, provider: Address, amount: i128) -> Result<i128, Error> {
+        provider.require_auth();
+        Self::extend_instance_ttl(&env);
+        if amount <= 0 {
+            return Err(Error::InvalidAmount);
+        }
+
+        let mut vault = Self::get_vault(env.clone());
+        if vault.total_shares == 0 && vault.balance > 0 {
+            // A balance without providers (fees or trader losses collected while the vault had no
+            // shares) goes to the insurance fund instead of the first depositor
+            Self::credit_insurance_fund(&env, vault.balance);
+            vault.balance = 0;
+        }
+        let shares = if vault.total_shares == 0 {
+            amount
+        } else {
+            let equity = Self::get_vault_equity(env.clone())?;
+            if equity <= 0 {
+                return Err(Error::InsufficientLiquidity);
+            }
+            fixed::mul_div(amount, vault.total_shares, equity, Rounding::Down)?
+        };
+        if shares == 0 {
+            return Err(Error::InvalidAmount);
+        }
+
+        Self::token_client(&env).transfer(&provider, &env.current_contract_address(), &amount);
+
+        vault.balance += amount;
+        vault.total_shares += shares;
+        env.storage().instance().set(&DataKey::Vault, &vault);
+
+        let provider_shares = Self::get_lp_shares(env.clone(), provider.clone()) + shares;
+        Self::save_lp_shares(&env, &provider, provider_shares);
+
+        events::liquidity_deposited(&env, &provider, LiquidityEvent { amount, shares });
+
+        Ok(shares)
+    }
+
+    // Redeem LP shares for their share of the vault, as long as the remaining liquidity still
+    // covers net trader exposure
+    pub fn withdraw_liquidity(env: Env, provider: Address, shares: i128) -> Result<i128, Error> {
+        provider.require_auth();
+        Self::extend_instance_ttl(&env);
+        let provider_shares = Self::get_lp_shares(env.clone(), provider.clone());
+        if shares <= 0 || shares > provider_shares {
+            return Err(Error::InvalidAmount);
+        }
+
+        // Shares are redeemed at the vault's equity, so providers can not exit ahead of losses
+        // that open positions have already made; never more than the vault holds
+        let mut vault = Self::get_vault(env.clone());
+        let equity = Self::get_vault_equity(env.clone())?;
+        let amount = fixed::mul_div(equity.max(0), shares, vault.total_shares, Rounding::Down)?
+            .min(vault.balance.max(0));
+        vault.balance -= amount;
+        vault.total_shares -= shares;
+        Self::check_utilization(&env, vault.balance)?;
+
+        env.storage().instance().set(&DataKey::Vault, &vault);
+        Self::save_lp_shares(&env, &provider, provider_shares - shares);
+
+        if amount > 0 {
+            Self::token_client(&env).transfer(&env.current_contract_address(), &provider, &amount);
+        }
+
+        events::liquidity_withdrawn(&env, &provider, LiquidityEvent { amount, shares });
+
+        Ok(amount)
+    }
+
+    // Top up the insurance fund with collateral tokens
+    pub fn deposit_insurance_fund(env: Env, from: Address, amount: i128) -> Result<(), Error> {
+        from.require_auth();
+        Self::extend_instance_ttl(&env);
+        if amount <= 0 {
+            return Err(Error::InvalidAmount);
+        }
+
+        Self::token_client(&env).transfer(&from, &env.current_contract_address(), &amount);
+        Self::credit_insurance_fund(&env, amount);
+
+        events::insurance_deposited(&env, &from, amount);
+
+        Ok(())
+    }
+
+    // Get position details
+    pub fn get_position(env: Env, position_id: u64) -> Result<Position, Error> {
+        env.storage()
+            .persistent()
+            .get(&DataKey::Position(position_id))
+            .ok_or(Error::PositionNotFound)
+    }
+
+    // Get the IDs of a user's open positions
+    pub fn get_user_positions(env: Env, user: Address) -> Vec<u64> {
+        env.storage()
+            .persistent()
+            .get(&DataKey::UserPositions(user))
+            .unwrap_or_else(|| vec![&env])
+    }
+
+    // Get a pending limit order
+    pub fn get_order(env: Env, order_id: u64) -> Result<Order, Error> {
+        env.storage()
+            .persistent()
+            .get(&DataKey::Order(order_id))
+            .ok_or(Error::OrderNotFound)
+    }
+
+    // Get the IDs of a user's pending limit orders
+    pub fn get_user_orders(env: Env, user: Address) -> Vec<u64> {
+        env.storage()
+            .persistent()
+            .get(&DataKey::UserOrders(user))
+            .unwrap_or_else(|| vec![&env])
+    }
+
+    // Get a user's net position on a one-way market, if they have one open
+    pub fn get_net_position(env: Env, user: Address, asset: ReflectorAsset) -> Option<Position> {
+        let position_id: u64 = env
+            .storage()
+            .persistent()
+            .get(&DataKey::NetPosition(user, asset))?;
+        Self::get_position(env, position_id)
+            .ok()
+            .filter(|position| position.is_open)
+    }
+
+    // Get total open positions count
+    pub fn get_total_positions(env: Env) -> u64 {
+        env.storage()
+            .instance()
+            .get(&DataKey::TotalPositions)
+            .unwrap_or(0)
+    }
+
+    // Get contract admin
+    pub fn get_admin(env: Env) -> Address {
+        env.storage().instance().get(&DataKey::Admin).unwrap()
+    }
+
+    // Get treasury address
+    pub fn get_treasury(env: Env) -> Address {
+        env.storage().instance().get(&DataKey::Treasury).unwrap()
+    }
+
+    // Get the token accepted as collateral
+    pub fn get_collateral_token(env: Env) -> Address {
+        env.storage()
             .instance()
-            .get(&symbol_short!("max_lev"))
+            .get(&DataKey::CollateralToken)
             .unwrap()
     }
 
-    // Get maintenance margin
-    pub fn get_maintenance_margin(env: Env) -> i128 {
-        env.storage()
-            .instance()
-            .get(&symbol_short!("marg"))
-            .unwrap()
+    // Get the collateral token balance earmarked for the insurance fund
+    pub fn get_insurance_fund(env: Env) -> i128 {
+        env.storage()
+            .instance()
+            .get(&DataKey::InsuranceFund)
+            .unwrap_or(0)
+    }
+
+    // Get losses that exceeded position collateral and could not be covered by the insurance fund
+    pub fn get_bad_debt(env: Env) -> i128 {
+        env.storage().instance().get(&DataKey::BadDebt).unwrap_or(0)
+    }
+
+    // Set how trading fees are split between the treasury, insurance fund and vault
+    pub fn set_fee_config(env: Env, config: FeeConfig) -> Result<(), Error> {
+        Self::require_admin(&env)?;
+        Self::extend_instance_ttl(&env);
+        if config.treasury_share < 0
+            || config.insurance_share < 0
+            || config.vault_share < 0
+            || config.treasury_share + config.insurance_share + config.vault_share
+                != fixed::BPS_DENOMINATOR
+        {
+            return Err(Error::InvalidAmount);
+        }
+        let mut last_volume = 0;
+        for tier in config.tiers.iter() {
+            if tier.min_volume <= last_volume
+                || tier.discount < 0
+                || tier.discount > fixed::BPS_DENOMINATOR
+            {
+                return Err(Error::InvalidAmount);
+            }
+            last_volume = tier.min_volume;
+        }
+        env.storage().instance().set(&DataKey::FeeConfig, &config);
+        events::fee_config_changed(&env, &config);
+        Ok(())
+    }
+
+    // Get the trading fee split; all fees go to the treasury until configured
+    pub fn get_fee_config(env: Env) -> FeeConfig {
+        env.storage()
+            .instance()
+            .get(&DataKey::FeeConfig)
+            .unwrap_or(FeeConfig {
+                treasury_share: fixed::BPS_DENOMINATOR,
+                insurance_share: 0,
+                vault_share: 0,
+                tiers: Vec::new(&env),
+            })
+    }
+
+    // Get the USD notional a user has opened and closed, which sets their fee tier
+    pub fn get_trading_volume(env: Env, user: Address) -> i128 {
+        env.storage()
+            .persistent()
+            .get(&DataKey::TradingVolume(user))
+            .unwrap_or(0)
+    }
+
+    // Get liquidity vault balance and outstanding shares
+    pub fn get_vault(env: Env) -> Vault {
+        env.storage()
+            .instance()
+            .get(&DataKey::Vault)
+            .unwrap_or(Vault {
+                balance: 0,
+                total_shares: 0,
+            })
+    }
+
+    // Get LP shares held by a liquidity provider
+    pub fn get_lp_shares(env: Env, provider: Address) -> i128 {
+        env.storage()
+            .persistent()
+            .get(&DataKey::LpShares(provider))
+            .unwrap_or(0)
+    }
+
+    // Get the maximum share of vault value, in basis points, that net trader exposure may use
+    pub fn get_max_utilization(env: Env) -> i128 {
+        env.storage()
+            .instance()
+            .get(&DataKey::MaxUtilization)
+            .unwrap_or(DEFAULT_MAX_UTILIZATION)
+    }
+
+    // Get the keeper fee escrowed with each new limit order and paid for triggering take profits and
+    // stop losses
+    pub fn get_execution_fee(env: Env) -> i128 {
+        env.storage()
+            .instance()
+            .get(&DataKey::ExecutionFee)
+            .unwrap_or(DEFAULT_EXECUTION_FEE)
+    }
+
+    // Set the keeper fee, in collateral tokens, for limit orders placed from now on
+    pub fn set_execution_fee(env: Env, execution_fee: i128) -> Result<(), Error> {
+        Self::require_admin(&env)?;
+        Self::extend_instance_ttl(&env);
+        if execution_fee < 0 {
+            return Err(Error::InvalidAmount);
+        }
+        env.storage()
+            .instance()
+            .set(&DataKey::ExecutionFee, &execution_fee);
+        events::execution_fee_changed(&env, execution_fee);
+        Ok(())
+    }
+
+    // Set the maximum vault utilization in basis points
+    pub fn set_max_utilization(env: Env, max_utilization: i128) -> Result<(), Error> {
+        Self::require_admin(&env)?;
+        Self::extend_instance_ttl(&env);
+        if max_utilization <= 0 || max_utilization > fixed::BPS_DENOMINATOR {
+            return Err(Error::InvalidAmount);
+        }
+        env.storage()
+            .instance()
+            .set(&DataKey::MaxUtilization, &max_utilization);
+        events::max_utilization_changed(&env, max_utilization);
+        Ok(())
+    }
+
+    // Set the bounds oracle prices are checked against
+    pub fn set_oracle_config(env: Env, config: OracleConfig) -> Result<(), Error> {
+        Self::require_admin(&env)?;
+        Self::extend_instance_ttl(&env);
+        if config.max_age_periods == 0 || config.max_deviation < 0 {
+            return Err(Error::InvalidAmount);
+        }
+        env.storage()
+            .instance()
+            .set(&DataKey::OracleConfig, &config);
+        events::oracle_config_changed(&env, &config);
+        Ok(())
+    }
+
+    // Get the decimals and resolution discovered for an oracle contract when it was configured
+    pub fn get_oracle_source(env: Env, oracle_address: Address) -> Result<OracleSource, Error> {
+        env.storage()
+            .instance()
+            .get(&DataKey::OracleSource(oracle_address))
+            .ok_or(Error::OracleError)
+    }
+
+    // Get the bounds oracle prices are checked against
+    pub fn get_oracle_config(env: Env) -> OracleConfig {
+        env.storage()
+            .instance()
+            .get(&DataKey::OracleConfig)
+            .unwrap_or(OracleConfig {
+                max_age_periods: DEFAULT_MAX_PRICE_AGE_PERIODS,
+                max_deviation: 0,
+            })
+    }
+
+    // Get minimum collateral requirement
+    pub fn get_min_collateral(env: Env) -> i128 {
+        env.storage()
+            .instance()
+            .get(&DataKey::MinCollateral)
+            .unwrap()
+    }
+
+    // Get maximum leverage
+    pub fn get_max_leverage(env: Env) -> u32 {
+        env.storage().instance().get(&DataKey::MaxLeverage).unwrap()
+    }
+
+    // Get maintenance margin
+    pub fn get_maintenance_margin(env: Env) -> i128 {
+        env.storage()
+            .instance()
+            .get(&DataKey::MaintenanceMargin)
+            .unwrap()
+    }
+
+    // List a new market; its limits must stay within the protocol-wide bounds set at initialize
+    pub fn add_market(env: Env, config: MarketConfig) -> Result<(), Error> {
+        Self::require_admin(&env)?;
+        Self::extend_instance_ttl(&env);
+
+        let key = DataKey::Market(config.asset.clone());
+        if env.storage().instance().has(&key) {
+            return Err(Error::MarketAlreadyExists);
+        }
+        Self::validate_market_config(&env, &config)?;
+        Self::discover_market_oracles(&env, &config)?;
+
+        env.storage().instance().set(&key, &config);
+        env.storage().instance().set(
+            &DataKey::Funding(config.asset.clone()),
+            &FundingRate {
+                asset: config.asset.clone(),
+                rate: 0,
+                cumulative_index: 0,
+                timestamp: env.ledger().timestamp(),
+            },
+        );
+
+        let mut markets = Self::get_market_assets(&env);
+        markets.push_back(config.asset.clone());
+        env.storage().instance().set(&DataKey::Markets, &markets);
+
+        events::market_config_changed(&env, &config);
+
+        Ok(())
+    }
+
+    // Add a collateral token to the whitelist or update its oracle asset, haircut or status
+    pub fn set_collateral_config(env: Env, config: CollateralConfig) -> Result<(), Error> {
+        Self::require_admin(&env)?;
+        Self::extend_instance_ttl(&env);
+        if config.haircut < 0 || config.haircut >= fixed::BPS_DENOMINATOR {
+            return Err(Error::InvalidAmount);
+        }
+        // Like in initialize, the settlement token is always active and valued without a haircut
+        if config.token == Self::get_collateral_token(env.clone())
+            && (config.haircut != 0 || !config.is_active)
+        {
+            return Err(Error::InvalidAmount);
+        }
+        if let Ok(oracle_address) = Self::get_oracle_address(&env) {
+            Self::discover_oracle_source(&env, &oracle_address, &vec![&env, config.asset.clone()])?;
+        }
+
+        let key = DataKey::Collateral(config.token.clone());
+        if !env.storage().instance().has(&key) {
+            let mut tokens = Self::get_collateral_tokens(env.clone());
+            tokens.push_back(config.token.clone());
+            env.storage()
+                .instance()
+                .set(&DataKey::CollateralTokens, &tokens);
+        }
+        env.storage().instance().set(&key, &config);
+
+        events::collateral_config_changed(&env, &config);
+
+        Ok(())
+    }
+
+    // Get the configuration of a whitelisted collateral token
+    pub fn get_collateral_config(env: Env, token: Address) -> Result<CollateralConfig, Error> {
+        env.storage()
+            .instance()
+            .get(&DataKey::Collateral(token))
+            .ok_or(Error::InvalidAsset)
+    }
+
+    // Get all whitelisted collateral tokens
+    pub fn get_collateral_tokens(env: Env) -> Vec<Address> {
+        env.storage()
+            .instance()
+            .get(&DataKey::CollateralTokens)
+            .unwrap_or_else(|| vec![&env])
+    }
+
+    // Replace the configuration of an existing market
+    pub fn update_market(env: Env, config: MarketConfig) -> Result<(), Error> {
+        Self::require_admin(&env)?;
+        Self::extend_instance_ttl(&env);
+
+        let key = DataKey::Market(config.asset.clone());
+        let current: MarketConfig = env
+            .storage()
+            .instance()
+            .get(&key)
+            .ok_or(Error::InvalidAsset)?;
+        Self::validate_market_config(&env, &config)?;
+
+        // Open positions were priced in the current quote asset and oracle decimals and netted
+        // under the current position mode, so none of these can change under them
+        let open_interest = Self::get_open_interest(env.clone(), config.asset.clone());
+        let has_open_interest = open_interest.long_size != 0 || open_interest.short_size != 0;
+        if has_open_interest
+            && (config.quote_asset != current.quote_asset
+                || config.position_mode != current.position_mode
+                || config.oracle_sources != current.oracle_sources)
+        {
+            return Err(Error::MarketHasOpenInterest);
+        }
+        let mut decimals = Vec::new(&env);
+        if has_open_interest {
+            for source in config.oracle_sources.iter() {
+                decimals.push_back(Self::get_oracle_source(env.clone(), source)?.decimals);
+            }
+        }
+        Self::discover_market_oracles(&env, &config)?;
+        for (source, source_decimals) in config.oracle_sources.iter().zip(decimals.iter()) {
+            if Self::get_oracle_source(env.clone(), source)?.decimals != source_decimals {
+                return Err(Error::MarketHasOpenInterest);
+            }
+        }
+
+        env.storage().instance().set(&key, &config);
+
+        events::market_config_changed(&env, &config);
+
+        Ok(())
+    }
+
+    // Stop new positions from being opened on a market; existing positions can still be closed
+    pub fn pause_market(env: Env, asset: ReflectorAsset) -> Result<(), Error> {
+        Self::require_admin(&env)?;
+        Self::extend_instance_ttl(&env);
+
+        let mut market = Self::get_market(env.clone(), asset)?;
+        market.is_active = false;
+        env.storage()
+            .instance()
+            .set(&DataKey::Market(market.asset.clone()), &market);
+
+        events::market_config_changed(&env, &market);
+
+        Ok(())
+    }
+
+    // Stop trading on a market whose oracle price has moved further from the last accepted price
+    // than the maximum deviation. Until the admin resets the circuit breaker, no positions can be
+    // opened or increased, and existing positions are closed, decreased and liquidated at the
+    // oracle price without the deviation check. Callable by anyone; returns whether the breaker
+    // was tripped.
+    pub fn trip_circuit_breaker(env: Env, asset: ReflectorAsset) -> Result<bool, Error> {
+        Self::extend_instance_ttl(&env);
+
+        let market = Self::get_market(env.clone(), asset.clone())?;
+        let price = Self::get_aggregate_price(
+            &env,
+            &market.oracle_sources,
+            market.oracle_quorum,
+            &asset,
+            &market.quote_asset,
+        )?;
+        let key = DataKey::LastPrice(asset.clone(), market.quote_asset.clone());
+        if Self::is_circuit_breaker_tripped(env.clone(), asset.clone())
+            || !Self::is_price_deviation_exceeded(&env, &key, price.value)?
+        {
+            return Ok(false);
+        }
+
+        env.storage()
+            .instance()
+            .set(&DataKey::CircuitBreaker(asset), &true);
+
+        events::circuit_breaker_tripped(&env, &market.asset, price.value);
+
+        Ok(true)
+    }
+
+    // Resume trading after a market's circuit breaker tripped, accepting the next oracle price as
+    // the new reference for the deviation check. A market paused by the admin stays paused.
+    pub fn reset_circuit_breaker(env: Env, asset: ReflectorAsset) -> Result<(), Error> {
+        Self::require_admin(&env)?;
+        Self::extend_instance_ttl(&env);
+
+        let market = Self::get_market(env.clone(), asset)?;
+        env.storage().instance().remove(&DataKey::LastPrice(
+            market.asset.clone(),
+            market.quote_asset.clone(),
+        ));
+        env.storage()
+            .instance()
+            .remove(&DataKey::CircuitBreaker(market.asset.clone()));
+
+        events::circuit_breaker_reset(&env, &market.asset);
+
+        Ok(())
+    }
+
+    // Check whether a market's circuit breaker is tripped
+    pub fn is_circuit_breaker_tripped(env: Env, asset: ReflectorAsset) -> bool {
+        env.storage()
+            .instance()
+            .has(&DataKey::CircuitBreaker(asset))
+    }
+
+    // Get the value of the vault to liquidity providers in collateral tokens: its balance less the
+    // net unrealized PnL of open positions, valued at each market's mark price. Paused and
+    // circuit-broken markets may have no usable price, so their positions are valued at the last
+    // price they were traded at instead.
+    pub fn get_vault_equity(env: Env) -> Result<i128, Error> {
+        let mut trader_pnl = Usd(0);
+        for asset in Self::get_market_assets(&env).iter() {
+            let open_interest = Self::get_open_interest(env.clone(), asset.clone());
+            if open_interest.long_size == 0 && open_interest.short_size == 0 {
+                continue;
+            }
+            let market = Self::get_market(env.clone(), asset.clone())?;
+            let market_pnl = if Self::is_tradable(&env, &market) {
+                let price = Self::get_mark_price(&env, &market)?;
+                Self::get_open_interest_pnl(&open_interest, price)?
+            } else {
+                Usd(env
+                    .storage()
+                    .instance()
+                    .get(&DataKey::SettledPnl(asset))
+                    .unwrap_or(0))
+            };
+            trader_pnl = trader_pnl.checked_add(market_pnl)?;
+        }
+
+        // Trader profits are counted in full and trader losses conservatively
+        let decimals = Self::token_client(&env).decimals();
+        let trader_pnl = Collateral::from_value(
+            trader_pnl,
+            decimals,
+            Self::get_collateral_price(&env)?,
+            Rounding::Up,
+        )?;
+        Self::get_vault(env)
+            .balance
+            .checked_sub(trader_pnl.amount)
+            .ok_or(Error::MathOverflow)
+    }
+
+    // Get market configuration
+    pub fn get_market(env: Env, asset: ReflectorAsset) -> Result<MarketConfig, Error> {
+        env.storage()
+            .instance()
+            .get(&DataKey::Market(asset))
+            .ok_or(Error::InvalidAsset)
+    }
+
+    // Get all listed markets
+    pub fn list_markets(env: Env) -> Vec<MarketConfig> {
+        let mut markets = vec![&env];
+        for asset in Self::get_market_assets(&env).iter() {
+            if let Ok(market) = Self::get_market(env.clone(), asset) {
+                markets.push_back(market);
+            }
+        }
+        markets
+    }
+
+    // Accrue funding for every elapsed interval of a market; callable by anyone
+    pub fn update_funding(env: Env, asset: ReflectorAsset) -> Result<FundingRate, Error> {
+        Self::extend_instance_ttl(&env);
+        let market = Self::get_market(env.clone(), asset)?;
+        Self::accrue_funding(&env, &market)
+    }
+
+    // Get the funding state of a market
+    pub fn get_funding_rate(env: Env, asset: ReflectorAsset) -> Result<FundingRate, Error> {
+        env.storage()
+            .instance()
+            .get(&DataKey::Funding(asset))
+            .ok_or(Error::InvalidAsset)
+    }
+
+    // Get long and short open interest of a market
+    pub fn get_open_interest(env: Env, asset: ReflectorAsset) -> OpenInterest {
+        env.storage()
+            .instance()
+            .get(&DataKey::OpenInterest(asset))
+            .unwrap_or(OpenInterest {
+                long_size: 0,
+                short_size: 0,
+                long_notional: 0,
+                short_notional: 0,
+            })
+    }
+
+    // Set the oracle used to price collateral
+    pub fn set_oracle(env: Env, oracle_address: Address) -> Result<(), Error> {
+        Self::require_admin(&env)?;
+        Self::extend_instance_ttl(&env);
+
+        // The oracle must quote every whitelisted collateral token
+        let mut assets = vec![&env];
+        for token in Self::get_collateral_tokens(env.clone()).iter() {
+            assets.push_back(Self::get_collateral_config(env.clone(), token)?.asset);
+        }
+        Self::discover_oracle_source(&env, &oracle_address, &assets)?;
+
+        env.storage()
+            .instance()
+            .set(&DataKey::Oracle, &oracle_address);
+
+        events::oracle_changed(&env, &oracle_address);
+
+        Ok(())
+    }
+
+    // Propose a new admin; the handover completes once the new admin accepts it
+    pub fn transfer_admin(env: Env, new_admin: Address) -> Result<(), Error> {
+        Self::require_admin(&env)?;
+        Self::extend_instance_ttl(&env);
+
+        env.storage()
+            .instance()
+            .set(&DataKey::PendingAdmin, &new_admin);
+
+        events::admin_proposed(&env, &new_admin);
+
+        Ok(())
+    }
+
+    // Accept a pending admin handover
+    pub fn accept_admin(env: Env, new_admin: Address) -> Result<(), Error> {
+        new_admin.require_auth();
+        Self::extend_instance_ttl(&env);
+
+        if Self::get_pending_admin(env.clone()) != Some(new_admin.clone()) {
+            return Err(Error::Unauthorized);
+        }
+
+        env.storage().instance().set(&DataKey::Admin, &new_admin);
+        env.storage().instance().remove(&DataKey::PendingAdmin);
+
+        events::admin_changed(&env, &new_admin);
+
+        Ok(())
+    }
+
+    // Get the admin proposed by transfer_admin, if any
+    pub fn get_pending_admin(env: Env) -> Option<Address> {
+        env.storage().instance().get(&DataKey::PendingAdmin)
+    }
+
+    // Extend the TTL of open positions and everything their owners have stored (see bump_users).
+    // Closed or unknown positions are skipped. Callable by anyone (e.g. a keeper); returns the
+    // number extended.
+    pub fn bump_positions(env: Env, position_ids: Vec<u64>) -> u32 {
+        Self::extend_instance_ttl(&env);
+
+        let mut bumped = 0;
+        for position_id in position_ids.iter() {
+            let Ok(position) = Self::get_position(env.clone(), position_id) else {
+                continue;
+            };
+            if !position.is_open {
+                continue;
+            }
+            Self::extend_persistent_ttl(&env, &DataKey::Position(position_id));
+            for key in [
+                DataKey::NetPosition(position.user.clone(), position.asset),
+                DataKey::TrailingStop(position_id),
+            ] {
+                if env.storage().persistent().has(&key) {
+                    Self::extend_persistent_ttl(&env, &key);
+                }
+            }
+            Self::extend_user_ttl(&env, &position.user);
+            bumped += 1;
+        }
+        bumped
+    }
+
+    // Extend the TTL of users' position indexes, margin accounts, limit orders, LP shares and
+    // trading volumes, e.g. for liquidity providers and users with only pending orders. Callable
+    // by anyone.
+    pub fn bump_users(env: Env, users: Vec<Address>) {
+        Self::extend_instance_ttl(&env);
+        for user in users.iter() {
+            Self::extend_user_ttl(&env, &user);
+        }
+    }
+
+    // Helper function to extend every persistent entry stored for a user that exists
+    fn extend_user_ttl(env: &Env, user: &Address) {
+        let mut keys = vec![
+            env,
+            DataKey::UserPositions(user.clone()),
+            DataKey::MarginAccount(user.clone()),
+            DataKey::UserOrders(user.clone()),
+            DataKey::LpShares(user.clone()),
+            DataKey::TradingVolume(user.clone()),
+        ];
+        for order_id in Self::get_user_orders(env.clone(), user.clone()).iter() {
+            keys.push_back(DataKey::Order(order_id));
+        }
+        for key in keys.iter() {
+            if env.storage().persistent().has(&key) {
+                Self::extend_persistent_ttl(env, &key);
+            }
+        }
+    }
+
+    // Helper function to require the stored admin's authorization
+    fn require_admin(env: &Env) -> Result<Address, Error> {
+        let admin: Address = env
+            .storage()
+            .instance()
+            .get(&DataKey::Admin)
+            .ok_or(Error::NotInitialized)?;
+        admin.require_auth();
+        Ok(admin)
+    }
+
+    // Helper function to extend the contract instance and everything stored in it
+    fn extend_instance_ttl(env: &Env) {
+        env.storage()
+            .instance()
+            .extend_ttl(INSTANCE_LIFETIME_THRESHOLD, INSTANCE_BUMP_AMOUNT);
+    }
+
+    // Helper function to extend a persistent entry
+    fn extend_persistent_ttl(env: &Env, key: &DataKey) {
+        env.storage().persistent().extend_ttl(
+            key,
+            POSITION_LIFETIME_THRESHOLD,
+            POSITION_BUMP_AMOUNT,
+        );
+    }
+
+    // Helper function to store a position, extending its TTL while it is open
+    fn save_position(env: &Env, position: &Position) {
+        let key = DataKey::Position(position.id);
+        env.storage().persistent().set(&key, position);
+        if position.is_open {
+            Self::extend_persistent_ttl(env, &key);
+        }
+    }
+
+    // Helper function to store a user's open position IDs, removing the entry once it is empty
+    fn save_user_positions(env: &Env, user: &Address, position_ids: &Vec<u64>) {
+        let key = DataKey::UserPositions(user.clone());
+        if position_ids.is_empty() {
+            env.storage().persistent().remove(&key);
+        } else {
+            env.storage().persistent().set(&key, position_ids);
+            Self::extend_persistent_ttl(env, &key);
+        }
+    }
+
+    // Helper function to store a user's pending order IDs, removing the entry once it is empty
+    fn save_user_orders(env: &Env, user: &Address, order_ids: &Vec<u64>) {
+        let key = DataKey::UserOrders(user.clone());
+        if order_ids.is_empty() {
+            env.storage().persistent().remove(&key);
+        } else {
+            env.storage().persistent().set(&key, order_ids);
+            Self::extend_persistent_ttl(env, &key);
+        }
+    }
+
+    // Helper function to store a margin account, removing it once it is empty
+    fn save_margin_account(env: &Env, user: &Address, account: &MarginAccount) {
+        let key = DataKey::MarginAccount(user.clone());
+        if account.balance == 0 && account.collateral.is_empty() && account.positions.is_empty() {
+            env.storage().persistent().remove(&key);
+        } else {
+            env.storage().persistent().set(&key, account);
+            Self::extend_persistent_ttl(env, &key);
+        }
+    }
+
+    // Helper function to return settled collateral to a user: transferred for an isolated
+    // position, credited back to the margin account for a cross-margin one
+    fn pay_out(env: &Env, user: &Address, cross_margin: bool, amount: i128) {
+        if amount <= 0 {
+            return;
+        }
+        if cross_margin {
+            let mut account = Self::get_margin_account(env.clone(), user.clone());
+            account.balance += amount;
+            Self::save_margin_account(env, user, &account);
+        } else {
+            Self::token_client(env).transfer(&env.current_contract_address(), user, &amount);
+        }
+    }
+
+    // Helper function to get the unrealized PnL of a position less its accrued funding
+    fn get_unrealized_pnl(
+        env: &Env,
+        position: &Position,
+        current_price: Price,
+    ) -> Result<Usd, Error> {
+        let entry_price = current_price.with_value(position.entry_price);
+        let pnl = Size(position.size).pnl(entry_price, current_price)?;
+        let funding_owed = Self::get_accrued_funding(env, position)?;
+        pnl.checked_sub(funding_owed)
+    }
+
+    // Helper function to value a cross-margin account and its margin requirements at mark prices
+    fn get_account_health(env: &Env, user: &Address) -> Result<AccountHealth, Error> {
+        let account = Self::get_margin_account(env.clone(), user.clone());
+        let mut health = AccountHealth {
+            equity: Self::get_margin_value(env, account.balance)?
+                .checked_add(Self::get_basket_value(env, &account)?)?,
+            unrealized_profit: Usd(0),
+            initial_margin: Usd(0),
+            maintenance_margin: Usd(0),
+        };
+        for position_id in account.positions.iter() {
+            let position = Self::get_position(env.clone(), position_id)?;
+            let market = Self::get_market(env.clone(), position.asset.clone())?;
+            let price = Self::get_mark_price(env, &market)?;
+
+            let entry_price = price.with_value(position.entry_price);
+            let pnl = Size(position.size).pnl(entry_price, price)?;
+            let funding_owed = Self::get_accrued_funding(env, &position)?;
+            let notional = Size(position.size).notional(price, Rounding::Up)?;
+
+            health.equity = health.equity.checked_add(pnl)?.checked_sub(funding_owed)?;
+            health.unrealized_profit = health.unrealized_profit.checked_add(Usd(pnl.0.max(0)))?;
+            health.initial_margin = health
+                .initial_margin
+                .checked_add(notional.div(position.leverage as i128, Rounding::Up)?)?;
+            health.maintenance_margin = health
+                .maintenance_margin
+                .checked_add(notional.bps(market.maintenance_margin, Rounding::Up)?)?;
+        }
+        Ok(health)
+    }
+
+    // Helper function to check that a cross-margin account, less unrealized profit, covers the
+    // initial margin of its positions
+    fn check_account_margin(env: &Env, user: &Address) -> Result<(), Error> {
+        let health = Self::get_account_health(env, user)?;
+        if health.equity.checked_sub(health.unrealized_profit)? < health.initial_margin {
+            return Err(Error::InsufficientCollateral);
+        }
+        Ok(())
+    }
+
+    // Helper function to store a trailing stop with the same TTL as its position
+    fn save_trailing_stop(env: &Env, trailing_stop: &TrailingStop) {
+        let key = DataKey::TrailingStop(trailing_stop.position_id);
+        env.storage().persistent().set(&key, trailing_stop);
+        Self::extend_persistent_ttl(env, &key);
+    }
+
+    // Helper function to delete an executed or cancelled order and drop it from its owner's index
+    fn remove_order(env: &Env, order: &Order) {
+        env.storage().persistent().remove(&DataKey::Order(order.id));
+        let mut user_orders = Self::get_user_orders(env.clone(), order.user.clone());
+        if let Some(index) = user_orders.first_index_of(order.id) {
+            user_orders.remove(index);
+        }
+        Self::save_user_orders(env, &order.user, &user_orders);
+    }
+
+    // Helper function to store a provider's LP shares, removing the entry once it is zero
+    fn save_lp_shares(env: &Env, provider: &Address, shares: i128) {
+        let key = DataKey::LpShares(provider.clone());
+        if shares == 0 {
+            env.storage().persistent().remove(&key);
+        } else {
+            env.storage().persistent().set(&key, &shares);
+            Self::extend_persistent_ttl(env, &key);
+        }
+    }
+
+    // Helper function to fill an order of `size` at the current price. In hedge mode it opens a new
+    // position; in one-way mode it nets with the user's position on the market: it is added to a
+    // position on the same side, and reduces, closes or flips a position on the other side.
+    // `escrowed` is set when the collateral is already held by the contract (limit orders).
+    #[allow(clippy::too_many_arguments)]
+    fn execute_market_order(
+        env: &Env,
+        user: &Address,
+        market: &MarketConfig,
+        size: i128,
+        leverage: u32,
+        collateral: i128,
+        escrowed: bool,
+    ) -> Result<u64, Error> {
+        if market.position_mode == PositionMode::OneWay {
+            if let Some(position) =
+                Self::get_net_position(env.clone(), user.clone(), market.asset.clone())
+            {
+                let position_id = position.id;
+                if (position.size > 0) == (size > 0) {
+                    Self::execute_increase(
+                        env,
+                        user,
+                        market,
+                        position,
+                        size.abs(),
+                        collateral,
+                        escrowed,
+                    )?;
+                    return Ok(position_id);
+                }
+
+                if size.abs() < position.size.abs() {
+                    Self::execute_decrease(env, user, market, position, size.abs())?;
+                    Self::refund_escrow(env, user, collateral, escrowed);
+                    return Ok(position_id);
+                }
+                let remaining_size = size + position.size;
+                Self::execute_close(env, user, market, position, None)?;
+                if remaining_size == 0 {
+                    Self::refund_escrow(env, user, collateral, escrowed);
+                    return Ok(position_id);
+                }
+                // The collateral, escrowed or not, backs the position opened for the remainder
+                return Self::execute_open(
+                    env,
+                    user,
+                    market,
+                    remaining_size,
+                    leverage,
+                    collateral,
+                    escrowed,
+                );
+            }
+        }
+
+        Self::execute_open(env, user, market, size, leverage, collateral, escrowed)
+    }
+
+    // Helper function to return escrowed order collateral that is not needed to reduce or close a
+    // position
+    fn refund_escrow(env: &Env, user: &Address, collateral: i128, escrowed: bool) {
+        if escrowed && collateral > 0 {
+            Self::token_client(env).transfer(&env.current_contract_address(), user, &collateral);
+        }
+    }
+
+    // Helper function to open a new position of `size` on a market
+    #[allow(clippy::too_many_arguments)]
+    fn execute_open(
+        env: &Env,
+        user: &Address,
+        market: &MarketConfig,
+        size: i128,
+        leverage: u32,
+        collateral: i128,
+        escrowed: bool,
+    ) -> Result<u64, Error> {
+        if collateral < market.min_collateral {
+            return Err(Error::InsufficientCollateral);
+        }
+
+        // Get current price from the market oracle
+        let price = Self::get_market_price(env, market)?;
+
+        // Initial margin is the position notional divided by leverage, rounded up
+        let notional = Size(size).notional(price, Rounding::Up)?;
+        let required_margin = notional.div(leverage as i128, Rounding::Up)?;
+
+        // The open fee is deducted from the collateral, which must still cover the initial margin
+        let open_fee = Self::get_fee_amount(env, user, notional, market.open_fee)?;
+        Self::record_volume(env, user, notional)?;
+        let collateral = collateral - open_fee;
+
+        // Collateral is valued at its oracle price less the haircut, rounded down
+        let collateral_value = Self::get_margin_value(env, collateral)?;
+        if collateral_value < required_margin {
+            return Err(Error::InsufficientCollateral);
+        }
+
+        // Escrow the collateral in the contract and distribute the fee
+        if !escrowed {
+            Self::token_client(env).transfer(
+                user,
+                &env.current_contract_address(),
+                &(collateral + open_fee),
+            );
+        }
+        Self::distribute_fee(env, open_fee)?;
+
+        Self::create_position(
+            env, user, market, size, leverage, collateral, price, open_fee, false,
+        )
+    }
+
+    // Helper function to record a new position opened at `price` and add it to open interest
+    #[allow(clippy::too_many_arguments)]
+    fn create_position(
+        env: &Env,
+        user: &Address,
+        market: &MarketConfig,
+        size: i128,
+        leverage: u32,
+        collateral: i128,
+        price: Price,
+        open_fee: i128,
+        cross_margin: bool,
+    ) -> Result<u64, Error> {
+        // Bring funding up to date before open interest changes
+        let funding = Self::accrue_funding(env, market)?;
+        let entry_notional = Size(size).notional(price, Rounding::Down)?;
+        Self::update_open_interest(env, &market.asset, size, entry_notional, true, price)?;
+
+        // The vault takes the other side of net exposure, up to its utilization limit
+        Self::check_utilization(env, Self::get_vault(env.clone()).balance)?;
+
+        // Generate position ID
+        let position_id = Self::get_next_position_id(env);
+
+        let position = Position {
+            id: position_id,
+            user: user.clone(),
+            asset: market.asset.clone(),
+            size,
+            leverage,
+            collateral,
+            entry_price: price.value,
+            timestamp: env.ledger().timestamp(),
+            is_open: true,
+            funding_index: funding.cumulative_index,
+            take_profit_price: None,
+            stop_loss_price: None,
+            cross_margin,
+        };
+
+        // Store position
+        Self::save_position(env, &position);
+
+        // Update user positions
+        let mut user_positions = Self::get_user_positions(env.clone(), user.clone());
+        user_positions.push_back(position_id);
+        Self::save_user_positions(env, user, &user_positions);
+        if cross_margin {
+            let mut account = Self::get_margin_account(env.clone(), user.clone());
+            account.positions.push_back(position_id);
+            Self::save_margin_account(env, user, &account);
+        } else if market.position_mode == PositionMode::OneWay {
+            let key = DataKey::NetPosition(user.clone(), market.asset.clone());
+            env.storage().persistent().set(&key, &position_id);
+            Self::extend_persistent_ttl(env, &key);
+        }
+
+        // Update total open positions
+        let total_positions = Self::get_total_positions(env.clone()) + 1;
+        env.storage()
+            .instance()
+            .set(&DataKey::TotalPositions, &total_positions);
+
+        events::position_opened(
+            env,
+            user,
+            PositionOpenedEvent {
+                position_id,
+                asset: position.asset,
+                size,
+                leverage,
+                collateral,
+                entry_price: price.value,
+                fee: open_fee,
+            },
+        );
+
+        Ok(position_id)
+    }
+
+    // Helper function to add to a position; see increase_position
+    fn execute_increase(
+        env: &Env,
+        user: &Address,
+        market: &MarketConfig,
+        mut position: Position,
+        size_delta: i128,
+        extra_collateral: i128,
+        escrowed: bool,
+    ) -> Result<(), Error> {
+        if size_delta <= 0 || extra_collateral < 0 {
+            return Err(Error::InvalidAmount);
+        }
+
+        let current_price = Self::get_market_price(env, market)?;
+
+        let added_size = if position.size > 0 {
+            size_delta
+        } else {
+            -size_delta
+        };
+        let notional = Size(added_size).notional(current_price, Rounding::Up)?;
+        let open_fee = Self::get_fee_amount(env, user, notional, market.open_fee)?;
+        Self::record_volume(env, user, notional)?;
+
+        // Settle funding with the vault, rounded against the trader, and restart from the current index
+        let funding = Self::accrue_funding(env, market)?;
+        let funding_owed = Self::get_accrued_funding(env, &position)?;
+        let funding_amount = -Self::usd_to_collateral(env, Usd(-funding_owed.0))?;
+        Self::update_vault_balance(env, funding_amount);
+        Self::check_vault_solvency(env)?;
+        position.funding_index = funding.cumulative_index;
+        position.collateral = position
+            .collateral
+            .checked_sub(funding_amount)
+            .and_then(|collateral| collateral.checked_add(extra_collateral - open_fee))
+            .ok_or(Error::MathOverflow)?;
+        if position.collateral <= 0 {
+            return Err(Error::InsufficientCollateral);
+        }
+
+        // Replace the position's entry notional in open interest
+        let entry_price = current_price.with_value(position.entry_price);
+        let entry_notional = Size(position.size).notional(entry_price, Rounding::Down)?;
+        Self::update_open_interest(
+            env,
+            &position.asset,
+            position.size,
+            entry_notional,
+            false,
+            current_price,
+        )?;
+
+        // Average the entry price, rounded against the trader
+        let old_size = position.size.abs();
+        let weighted_price = old_size
+            .checked_mul(position.entry_price)
+            .and_then(|old| {
+                size_delta
+                    .checked_mul(current_price.value)?
+                    .checked_add(old)
+            })
+            .ok_or(Error::MathOverflow)?;
+        let rounding = if position.size > 0 {
+            Rounding::Up
+        } else {
+            Rounding::Down
+        };
+        position.entry_price = fixed::mul_div(weighted_price, 1, old_size + size_delta, rounding)?;
+        position.size += added_size;
+
+        let entry_price = current_price.with_value(position.entry_price);
+        let entry_notional = Size(position.size).notional(entry_price, Rounding::Down)?;
+        Self::update_open_interest(
+            env,
+            &position.asset,
+            position.size,
+            entry_notional,
+            true,
+            current_price,
+        )?;
+
+        Self::check_initial_margin(env, &position, market, current_price)?;
+        Self::check_utilization(env, Self::get_vault(env.clone()).balance)?;
+
+        position.leverage = Self::get_effective_leverage(env, &position, current_price)?;
+        Self::save_position(env, &position);
+
+        if extra_collateral > 0 && !escrowed {
+            Self::token_client(env).transfer(
+                user,
+                &env.current_contract_address(),
+                &extra_collateral,
+            );
+        }
+        Self::distribute_fee(env, open_fee)?;
+
+        events::position_increased(
+            env,
+            user,
+            PositionIncreasedEvent {
+                position_id: position.id,
+                asset: position.asset,
+                size_delta: added_size,
+                size: position.size,
+                collateral: position.collateral,
+                entry_price: position.entry_price,
+                price: current_price.value,
+                funding: funding_owed.0,
+                fee: open_fee,
+            },
+        );
+
+        Ok(())
+    }
+
+    // Helper function to close part of a position; see decrease_position
+    fn execute_decrease(
+        env: &Env,
+        user: &Address,
+        market: &MarketConfig,
+        mut position: Position,
+        size_delta: i128,
+    ) -> Result<(), Error> {
+        if size_delta <= 0 || size_delta >= position.size.abs() {
+            return Err(Error::InvalidAmount);
+        }
+        let current_price = Self::get_market_price(env, market)?;
+
+        let closed_size = if position.size > 0 {
+            size_delta
+        } else {
+            -size_delta
+        };
+        let closed_collateral = fixed::mul_div(
+            position.collateral,
+            size_delta,
+            position.size.abs(),
+            Rounding::Down,
+        )?;
+        let notional = Size(closed_size).notional(current_price, Rounding::Up)?;
+        let close_fee = Self::get_fee_amount(env, user, notional, market.close_fee)?;
+        Self::record_volume(env, user, notional)?;
+
+        let settlement = Self::realize(
+            env,
+            &position,
+            market,
+            closed_size,
+            closed_collateral,
+            current_price,
+        )?;
+        position.size -= closed_size;
+        position.collateral -= closed_collateral;
+
+        // The vault pays out profits and collects losses on the closed size
+        Self::update_vault_balance(env, closed_collateral - settlement.final_collateral);
+        Self::check_vault_solvency(env)?;
+
+        let mut payout = settlement.final_collateral;
+        if payout < 0 {
+            position.collateral += payout;
+            payout = 0;
+        }
+        let close_fee = close_fee.min(payout);
+        payout -= close_fee;
+        Self::distribute_fee(env, close_fee)?;
+
+        // The rest of the position must stay healthy
+        if position.collateral <= 0
+            || Self::is_position_liquidatable(
+                env,
+                &position,
+                market,
+                Self::get_mark_price(env, market)?,
+            )?
+        {
+            return Err(Error::MaintenanceMarginNotMet);
+        }
+
+        position.leverage = Self::get_effective_leverage(env, &position, current_price)?;
+        Self::save_position(env, &position);
+
+        if payout > 0 {
+            Self::token_client(env).transfer(&env.current_contract_address(), user, &payout);
+        }
+
+        events::position_decreased(
+            env,
+            user,
+            PositionDecreasedEvent {
+                position_id: position.id,
+                asset: position.asset,
+                size_delta: closed_size,
+                size: position.size,
+                collateral: position.collateral,
+                exit_price: current_price.value,
+                realized_pnl: settlement.pnl.0,
+                funding: settlement.funding.0,
+                fee: close_fee,
+                payout,
+            },
+        );
+
+        Ok(())
     }
 
-    pub fn set_oracle(env: Env, oracle_address: Address) {
-        env.storage()
-            .instance()
-            .set(&symbol_short!("oracle"), &oracle_address);
+    // Helper function to close a whole position at the current price and pay out what is left
+    fn execute_close(
+        env: &Env,
+        user: &Address,
+        market: &MarketConfig,
+        position: Position,
+        keeper: Option<&Address>,
+    ) -> Result<i128, Error> {
+        // Get current price from the market oracle
+        let current_price = Self::get_market_price(env, market)?;
+
+        // The close fee is charged on the current notional
+        let notional = Size(position.size).notional(current_price, Rounding::Up)?;
+        let close_fee = Self::get_fee_amount(env, user, notional, market.close_fee)?;
+        Self::record_volume(env, user, notional)?;
+
+        // Settle PnL and funding against the collateral and the vault
+        let position_id = position.id;
+        let asset = position.asset.clone();
+        let cross_margin = position.cross_margin;
+        let settlement = Self::settle_position(env, position, market, current_price)?;
+        Self::check_vault_solvency(env)?;
+
+        // The fee is capped by what is left of the collateral
+        let close_fee = close_fee.min(settlement.final_collateral.max(0));
+        let mut final_collateral = settlement.final_collateral - close_fee;
+        Self::distribute_fee(env, close_fee)?;
+
+        // A keeper closing the position on the user's behalf is paid the execution fee, capped
+        // the same way
+        let mut keeper_fee = 0;
+        if let Some(keeper) = keeper {
+            keeper_fee = Self::get_execution_fee(env.clone()).min(final_collateral.max(0));
+            final_collateral -= keeper_fee;
+            if keeper_fee > 0 {
+                Self::token_client(env).transfer(
+                    &env.current_contract_address(),
+                    keeper,
+                    &keeper_fee,
+                );
+            }
+        }
+
+        // Return final collateral to the user
+        Self::pay_out(env, user, cross_margin, final_collateral);
+
+        events::position_closed(
+            env,
+            user,
+            PositionClosedEvent {
+                position_id,
+                asset,
+                exit_price: current_price.value,
+                realized_pnl: settlement.pnl.0,
+                funding: settlement.funding.0,
+                fee: close_fee,
+                payout: final_collateral.max(0),
+            },
+        );
+
+        Ok(keeper_fee)
+    }
+
+    // Helper function to load an open position owned by `user`
+    fn get_open_position(env: &Env, user: &Address, position_id: u64) -> Result<Position, Error> {
+        let position = Self::get_position(env.clone(), position_id)?;
+        if !position.is_open {
+            return Err(Error::PositionNotFound);
+        }
+        if position.user != *user {
+            return Err(Error::Unauthorized);
+        }
+        Ok(position)
+    }
+
+    // Helper function to load an open isolated-margin position owned by `user`
+    fn get_isolated_position(
+        env: &Env,
+        user: &Address,
+        position_id: u64,
+    ) -> Result<Position, Error> {
+        let position = Self::get_open_position(env, user, position_id)?;
+        if position.cross_margin {
+            return Err(Error::IsolatedOnly);
+        }
+        Ok(position)
+    }
+
+    // Helper function to get the leverage of a position from its current notional and collateral
+    // value, rounded up
+    fn get_effective_leverage(
+        env: &Env,
+        position: &Position,
+        current_price: Price,
+    ) -> Result<u32, Error> {
+        let notional = Size(position.size).notional(current_price, Rounding::Up)?;
+        let collateral_value = Self::get_margin_value(env, position.collateral)?;
+        if collateral_value.0 <= 0 {
+            return Err(Error::InsufficientCollateral);
+        }
+        let leverage = fixed::mul_div(notional.0, 1, collateral_value.0, Rounding::Up)?;
+        u32::try_from(leverage.max(1)).map_err(|_| Error::MathOverflow)
     }
 
     // Helper function to get next position ID
@@ -376,42 +2758,891 @@ impl PerpetualFutures {
         let current_id = env
             .storage()
             .instance()
-            .get(&symbol_short!("next_id"))
+            .get(&DataKey::NextPositionId)
+            .unwrap_or(0);
+        env.storage()
+            .instance()
+            .set(&DataKey::NextPositionId, &(current_id + 1));
+        current_id + 1
+    }
+
+    // Helper function to get next order ID
+    fn get_next_order_id(env: &Env) -> u64 {
+        let current_id = env
+            .storage()
+            .instance()
+            .get(&DataKey::NextOrderId)
             .unwrap_or(0);
         env.storage()
             .instance()
-            .set(&symbol_short!("next_id"), &(current_id + 1));
+            .set(&DataKey::NextOrderId, &(current_id + 1));
         current_id + 1
     }
 
+    // Helper function to get the assets of all listed markets
+    fn get_market_assets(env: &Env) -> Vec<ReflectorAsset> {
+        env.storage()
+            .instance()
+            .get(&DataKey::Markets)
+            .unwrap_or_else(|| vec![env])
+    }
+
+    // Helper function to check a market configuration against the protocol-wide bounds
+    fn validate_market_config(env: &Env, config: &MarketConfig) -> Result<(), Error> {
+        if config.max_leverage == 0 || config.max_leverage > Self::get_max_leverage(env.clone()) {
+            return Err(Error::InvalidLeverage);
+        }
+        if config.min_collateral < Self::get_min_collateral(env.clone()) {
+            return Err(Error::InsufficientCollateral);
+        }
+        if config.maintenance_margin <= 0 || config.maintenance_margin >= 10_000 {
+            return Err(Error::InvalidAmount);
+        }
+        if config.funding_rate_interval == 0 || config.max_funding_rate < 0 {
+            return Err(Error::InvalidAmount);
+        }
+        if config.liquidation_fee < 0 || config.liquidation_fee >= config.maintenance_margin {
+            return Err(Error::InvalidAmount);
+        }
+        if config.open_fee < 0 || config.close_fee < 0 {
+            return Err(Error::InvalidAmount);
+        }
+        if config.oracle_quorum == 0 || config.oracle_quorum > config.oracle_sources.len() {
+            return Err(Error::InvalidAmount);
+        }
+        if config.quote_asset == QuoteAsset::Asset(config.asset.clone()) {
+            return Err(Error::InvalidAsset);
+        }
+        Ok(())
+    }
+
+    // Helper function to apply the funding rate for every full interval elapsed since the last update.
+    // The rate is driven by the open interest imbalance: the crowded side pays the other.
+    fn accrue_funding(env: &Env, market: &MarketConfig) -> Result<FundingRate, Error> {
+        let mut funding = Self::get_funding_rate(env.clone(), market.asset.clone())?;
+
+        let intervals =
+            (env.ledger().timestamp() - funding.timestamp) / market.funding_rate_interval;
+        if intervals == 0 {
+            return Ok(funding);
+        }
+
+        let open_interest = Self::get_open_interest(env.clone(), market.asset.clone());
+        let total_size = open_interest.long_size + open_interest.short_size;
+        funding.rate = if total_size == 0 {
+            0
+        } else {
+            fixed::mul_div(
+                market.max_funding_rate,
+                open_interest.long_size - open_interest.short_size,
+                total_size,
+                Rounding::Down,
+            )?
+        };
+        // Funding accrues on the value of the open interest at the mark price
+        if funding.rate != 0 {
+            let unit_value = Size(fixed::pow10(fixed::SIZE_DECIMALS)?)
+                .notional(Self::get_mark_price(env, market)?, Rounding::Down)?;
+            funding.cumulative_index = funding
+                .rate
+                .checked_mul(intervals as i128)
+                .and_then(|rate| rate.checked_mul(unit_value.0))
+                .and_then(|accrued| funding.cumulative_index.checked_add(accrued))
+                .ok_or(Error::MathOverflow)?;
+        }
+        funding.timestamp += intervals * market.funding_rate_interval;
+
+        env.storage()
+            .instance()
+            .set(&DataKey::Funding(market.asset.clone()), &funding);
+
+        events::funding_updated(env, &funding);
+
+        Ok(funding)
+    }
+
+    // Helper function to get the funding a position owes since it was opened (negative when it is owed funding).
+    // Funding is charged on the mark value of the position at each accrual and rounded up.
+    fn get_accrued_funding(env: &Env, position: &Position) -> Result<Usd, Error> {
+        let funding = Self::get_funding_rate(env.clone(), position.asset.clone())?;
+        let index_delta = funding
+            .cumulative_index
+            .checked_sub(position.funding_index)
+            .ok_or(Error::MathOverflow)?;
+        let denominator = fixed::pow10(fixed::SIZE_DECIMALS)?
+            .checked_mul(FUNDING_PRECISION)
+            .ok_or(Error::MathOverflow)?;
+        Ok(Usd(fixed::mul_div(
+            position.size,
+            index_delta,
+            denominator,
+            Rounding::Up,
+        )?))
+    }
+
+    // Helper function to get the mark price of a market: the median time-weighted average of its
+    // sources' recent prices, used to decide liquidations and value funding so that a single tick
+    // can not trigger them. The spot price is always read as well so the feeds are checked for
+    // staleness.
+    fn get_mark_price(env: &Env, market: &MarketConfig) -> Result<Price, Error> {
+        let spot_price = Self::get_market_price(env, market)?;
+        if market.mark_price_records == 0 {
+            return Ok(spot_price);
+        }
+        let mut twaps = vec![env];
+        for source in market.oracle_sources.iter() {
+            let reflector_client = ReflectorClient::new(env, &source);
+            let twap = match &market.quote_asset {
+                QuoteAsset::Asset(quote_asset) => reflector_client.try_x_twap(
+                    &market.asset,
+                    quote_asset,
+                    &market.mark_price_records,
+                ),
+                QuoteAsset::Usd => {
+                    reflector_client.try_twap(&market.asset, &market.mark_price_records)
+                }
+            }
+            .ok()
+            .and_then(Result::ok)
+            .flatten();
+            let oracle = Self::get_oracle_source(env.clone(), source).ok();
+            if let (Some(twap), Some(oracle)) = (twap, oracle) {
+                if twap > 0 {
+                    twaps.push_back(
+                        Price::new(twap, oracle.decimals)
+                            .rescale(PRICE_DECIMALS)?
+                            .value,
+                    );
+                }
+            }
+        }
+        if twaps.len() < market.oracle_quorum {
+            return match market.mark_price_fallback {
+                MarkPriceFallback::Spot => Ok(spot_price),
+                MarkPriceFallback::Reject => Err(Error::OracleError),
+            };
+        }
+        Ok(spot_price.with_value(Self::get_median(env, &twaps)))
+    }
+
+    // Helper function to check whether new positions can be opened on a market: it must be active
+    // and its circuit breaker must not be tripped
+    fn is_tradable(env: &Env, market: &MarketConfig) -> bool {
+        market.is_active && !Self::is_circuit_breaker_tripped(env.clone(), market.asset.clone())
+    }
+
+    // Helper function to get the spot price of a market from its oracle sources. The price of a
+    // market quoted in another asset carries the quote asset's USD price from the same sources, so
+    // notional values and PnL come out in USD. While the market's circuit breaker is tripped the
+    // price is still checked for quorum and staleness, but not against the last accepted price,
+    // so positions can be settled until the admin resets the reference.
+    fn get_market_price(env: &Env, market: &MarketConfig) -> Result<Price, Error> {
+        let sources = &market.oracle_sources;
+        let price = if Self::is_circuit_breaker_tripped(env.clone(), market.asset.clone()) {
+            Self::get_aggregate_price(
+                env,
+                sources,
+                market.oracle_quorum,
+                &market.asset,
+                &market.quote_asset,
+            )?
+        } else {
+            Self::get_oracle_price(
+                env,
+                sources,
+                market.oracle_quorum,
+                &market.asset,
+                &market.quote_asset,
+            )?
+        };
+        let QuoteAsset::Asset(quote_asset) = &market.quote_asset else {
+            return Ok(price);
+        };
+        let quote_price = Self::get_aggregate_price(
+            env,
+            &market.oracle_sources,
+            market.oracle_quorum,
+            quote_asset,
+            &QuoteAsset::Usd,
+        )?;
+        Ok(price.in_quote(quote_price))
+    }
+
+    // Helper function to read the price of a market's asset, in USD or in its quote asset. The
+    // median of its oracle sources must be within the maximum deviation from the last accepted
+    // price, which it then replaces. Only market prices are checked, as a move rejected here is
+    // recovered from through the market's circuit breaker; collateral and quote asset prices are
+    // read with get_aggregate_price alone.
+    fn get_oracle_price(
+        env: &Env,
+        sources: &Vec<Address>,
+        quorum: u32,
+        asset: &ReflectorAsset,
+        quote_asset: &QuoteAsset,
+    ) -> Result<Price, Error> {
+        let price = Self::get_aggregate_price(env, sources, quorum, asset, quote_asset)?;
+        let key = DataKey::LastPrice(asset.clone(), quote_asset.clone());
+        if Self::is_price_deviation_exceeded(env, &key, price.value)? {
+            return Err(Error::PriceDeviation);
+        }
+        env.storage().instance().set(&key, &price.value);
+        Ok(price)
+    }
+
+    // Helper function to aggregate the latest prices of an asset (in USD, or cross prices in a
+    // quote asset) from a list of oracle sources into their median. Sources that fail, have no
+    // price, report a non-positive price or one older than the configured number of their
+    // resolution periods are skipped, as long as `quorum` of them still report a price. Missing the
+    // quorum only because of stale prices fails with PriceStale rather than OracleError.
+    fn get_aggregate_price(
+        env: &Env,
+        sources: &Vec<Address>,
+        quorum: u32,
+        asset: &ReflectorAsset,
+        quote_asset: &QuoteAsset,
+    ) -> Result<Price, Error> {
+        let max_age_periods = Self::get_oracle_config(env.clone()).max_age_periods as u64;
+        let mut prices = vec![env];
+        let mut stale_sources = 0;
+        for source in sources.iter() {
+            let reflector_client = ReflectorClient::new(env, &source);
+            let price_data = match quote_asset {
+                QuoteAsset::Asset(quote_asset) => {
+                    reflector_client.try_x_last_price(asset, quote_asset)
+                }
+                QuoteAsset::Usd => reflector_client.try_lastprice(asset),
+            }
+            .ok()
+            .and_then(Result::ok)
+            .flatten();
+            let Some(price_data) = price_data else {
+                continue;
+            };
+            let Ok(oracle) = Self::get_oracle_source(env.clone(), source) else {
+                continue;
+            };
+            let age = env
+                .ledger()
+                .timestamp()
+                .saturating_sub(price_data.timestamp);
+            if price_data.price <= 0 {
+                continue;
+            }
+            if age > oracle.resolution as u64 * max_age_periods {
+                stale_sources += 1;
+                continue;
+            }
+            prices.push_back(
+                Price::new(price_data.price, oracle.decimals)
+                    .rescale(PRICE_DECIMALS)?
+                    .value,
+            );
+        }
+        if prices.is_empty() || prices.len() < quorum {
+            if stale_sources > 0 && prices.len() + stale_sources == sources.len() {
+                return Err(Error::PriceStale);
+            }
+            return Err(Error::OracleError);
+        }
+        Ok(Price::new(Self::get_median(env, &prices), PRICE_DECIMALS))
+    }
+
+    // Helper function to query the decimals, resolution and quoted assets of an oracle contract,
+    // caching the first two so prices from it are scaled and checked for staleness accordingly.
+    // Fails with InvalidAsset if the oracle does not quote all of `assets`.
+    fn discover_oracle_source(
+        env: &Env,
+        oracle_address: &Address,
+        assets: &Vec<ReflectorAsset>,
+    ) -> Result<OracleSource, Error> {
+        let reflector_client = ReflectorClient::new(env, oracle_address);
+        let decimals = reflector_client.try_decimals().ok().and_then(Result::ok);
+        let resolution = reflector_client.try_resolution().ok().and_then(Result::ok);
+        let quoted_assets = reflector_client.try_assets().ok().and_then(Result::ok);
+        let (Some(decimals), Some(resolution), Some(quoted_assets)) =
+            (decimals, resolution, quoted_assets)
+        else {
+            return Err(Error::OracleError);
+        };
+        if resolution == 0 {
+            return Err(Error::OracleError);
+        }
+        if assets.iter().any(|asset| !quoted_assets.contains(&asset)) {
+            return Err(Error::InvalidAsset);
+        }
+
+        let oracle = OracleSource {
+            decimals,
+            resolution,
+        };
+        env.storage()
+            .instance()
+            .set(&DataKey::OracleSource(oracle_address.clone()), &oracle);
+        Ok(oracle)
+    }
+
+    // Helper function to discover every oracle source of a market, each of which must quote the
+    // market's asset and, for a cross-price market, its quote asset
+    fn discover_market_oracles(env: &Env, config: &MarketConfig) -> Result<(), Error> {
+        let mut assets = vec![env, config.asset.clone()];
+        if let QuoteAsset::Asset(quote_asset) = &config.quote_asset {
+            assets.push_back(quote_asset.clone());
+        }
+        for source in config.oracle_sources.iter() {
+            Self::discover_oracle_source(env, &source, &assets)?;
+        }
+        Ok(())
+    }
+
+    // Helper function to get the median of a non-empty list of prices, averaging the middle two of
+    // an even number of them
+    fn get_median(env: &Env, prices: &Vec<i128>) -> i128 {
+        let mut sorted: Vec<i128> = vec![env];
+        for price in prices.iter() {
+            let index = sorted
+                .iter()
+                .take_while(|sorted_price| *sorted_price <= price)
+                .count();
+            sorted.insert(index as u32, price);
+        }
+        let middle = sorted.len() / 2;
+        let upper = sorted.get_unchecked(middle);
+        if sorted.len() % 2 == 1 {
+            return upper;
+        }
+        let lower = sorted.get_unchecked(middle - 1);
+        lower + (upper - lower) / 2
+    }
+
+    // Helper function to check whether a price moved further from the last accepted price stored
+    // under a LastPrice key than the configured maximum deviation
+    fn is_price_deviation_exceeded(env: &Env, key: &DataKey, price: i128) -> Result<bool, Error> {
+        let max_deviation = Self::get_oracle_config(env.clone()).max_deviation;
+        let last_price: Option<i128> = env.storage().instance().get(key);
+        let Some(last_price) = last_price else {
+            return Ok(false);
+        };
+        if max_deviation == 0 {
+            return Ok(false);
+        }
+        let deviation = fixed::mul_div(
+            (price - last_price).abs(),
+            fixed::BPS_DENOMINATOR,
+            last_price,
+            Rounding::Up,
+        )?;
+        Ok(deviation > max_deviation)
+    }
+
+    // Helper function to get the USD price of a collateral token from the global oracle
+    fn get_token_price(env: &Env, config: &CollateralConfig) -> Result<Price, Error> {
+        let oracle_address = Self::get_oracle_address(env)?;
+        Self::get_aggregate_price(
+            env,
+            &vec![env, oracle_address],
+            1,
+            &config.asset,
+            &QuoteAsset::Usd,
+        )
+    }
+
+    // Helper function to get the USD price of the collateral token
+    fn get_collateral_price(env: &Env) -> Result<Price, Error> {
+        let config =
+            Self::get_collateral_config(env.clone(), Self::get_collateral_token(env.clone()))?;
+        Self::get_token_price(env, &config)
+    }
+
+    // Helper function to value an amount of a collateral token as margin: its USD value less the
+    // token's haircut, rounded down. Debts (negative amounts) are valued in full.
+    fn get_token_margin_value(
+        env: &Env,
+        config: &CollateralConfig,
+        amount: i128,
+    ) -> Result<Usd, Error> {
+        let decimals = token::Client::new(env, &config.token).decimals();
+        let value = Collateral::new(amount, decimals)
+            .value(Self::get_token_price(env, config)?, Rounding::Down)?;
+        if amount <= 0 {
+            return Ok(value);
+        }
+        value.bps(fixed::BPS_DENOMINATOR - config.haircut, Rounding::Down)
+    }
+
+    // Helper function to value an amount of the collateral token as margin
+    fn get_margin_value(env: &Env, amount: i128) -> Result<Usd, Error> {
+        let config =
+            Self::get_collateral_config(env.clone(), Self::get_collateral_token(env.clone()))?;
+        Self::get_token_margin_value(env, &config, amount)
+    }
+
+    // Helper function to value the other collateral of a margin account as margin
+    fn get_basket_value(env: &Env, account: &MarginAccount) -> Result<Usd, Error> {
+        let mut value = Usd(0);
+        for (token, amount) in account.collateral.iter() {
+            let config = Self::get_collateral_config(env.clone(), token)?;
+            value = value.checked_add(Self::get_token_margin_value(env, &config, amount)?)?;
+        }
+        Ok(value)
+    }
+
+    // Helper function to repay the debt of a margin account: the liquidator pays collateral tokens
+    // into the vault and receives the account's other collateral at its haircut value
+    fn seize_collateral(env: &Env, liquidator: &Address, user: &Address) -> Result<(), Error> {
+        let mut account = Self::get_margin_account(env.clone(), user.clone());
+        for (token, held) in account.collateral.clone().iter() {
+            if account.balance >= 0 {
+                break;
+            }
+            let config = Self::get_collateral_config(env.clone(), token.clone())?;
+            let held_value = Self::get_token_margin_value(env, &config, held)?;
+            let debt_value = Usd(-Self::get_collateral_value(env, account.balance)?.0);
+
+            // Seize just enough to cover the debt, or everything if that is not enough
+            let seized = if held_value <= debt_value {
+                held
+            } else {
+                fixed::mul_div(held, debt_value.0, held_value.0, Rounding::Up)?
+            };
+            let repaid =
+                Self::usd_to_collateral(env, Self::get_token_margin_value(env, &config, seized)?)?
+                    .min(-account.balance);
+
+            Self::token_client(env).transfer(liquidator, &env.current_contract_address(), &repaid);
+            token::Client::new(env, &token).transfer(
+                &env.current_contract_address(),
+                liquidator,
+                &seized,
+            );
+            account.balance += repaid;
+            if seized == held {
+                account.collateral.remove(token.clone());
+            } else {
+                account.collateral.set(token.clone(), held - seized);
+            }
+
+            events::collateral_seized(
+                env,
+                user,
+                CollateralSeizedEvent {
+                    token,
+                    liquidator: liquidator.clone(),
+                    amount: seized,
+                    repaid,
+                },
+            );
+        }
+
+        // settle_position already credited the vault with the debt. Once there is no collateral
+        // or position left to repay it from, the rest is a loss for the insurance fund and then
+        // bad debt, like any loss beyond a position's collateral.
+        if account.balance < 0 && account.collateral.is_empty() && account.positions.is_empty() {
+            let deficit = -account.balance;
+            let covered = Self::cover_bad_debt(env, deficit);
+            Self::update_vault_balance(env, covered - deficit);
+            account.balance = 0;
+        }
+        Self::save_margin_account(env, user, &account);
+        Ok(())
+    }
+
+    // Helper function to value a collateral token amount in USD, rounded down
+    fn get_collateral_value(env: &Env, amount: i128) -> Result<Usd, Error> {
+        let decimals = Self::token_client(env).decimals();
+        Collateral::new(amount, decimals).value(Self::get_collateral_price(env)?, Rounding::Down)
+    }
+
+    // Helper function to convert a signed USD amount into collateral tokens, rounded down
+    // (profits are paid out conservatively, losses are charged in full)
+    fn usd_to_collateral(env: &Env, value: Usd) -> Result<i128, Error> {
+        let decimals = Self::token_client(env).decimals();
+        let collateral = Collateral::from_value(
+            value,
+            decimals,
+            Self::get_collateral_price(env)?,
+            Rounding::Down,
+        )?;
+        Ok(collateral.amount)
+    }
+
+    // Helper function to convert a fee in basis points of a USD notional into collateral tokens,
+    // rounded up, after the discount of the volume tier the user has reached
+    fn get_fee_amount(env: &Env, user: &Address, notional: Usd, fee: i128) -> Result<i128, Error> {
+        let volume = Self::get_trading_volume(env.clone(), user.clone());
+        let discount = Self::get_fee_config(env.clone())
+            .tiers
+            .iter()
+            .filter(|tier| volume >= tier.min_volume)
+            .map(|tier| tier.discount)
+            .last()
+            .unwrap_or(0);
+        if fee == 0 || discount == fixed::BPS_DENOMINATOR {
+            return Ok(0);
+        }
+        let decimals = Self::token_client(env).decimals();
+        let collateral = Collateral::from_value(
+            notional.bps(fee, Rounding::Up)?,
+            decimals,
+            Self::get_collateral_price(env)?,
+            Rounding::Up,
+        )?;
+        fixed::mul_div(
+            collateral.amount,
+            fixed::BPS_DENOMINATOR - discount,
+            fixed::BPS_DENOMINATOR,
+            Rounding::Up,
+        )
+    }
+
+    // Helper function to add a fill's notional to the user's trading volume. Fills are recorded
+    // after their fee is taken, so a fill is charged at the tier reached before it.
+    fn record_volume(env: &Env, user: &Address, notional: Usd) -> Result<(), Error> {
+        let volume = Self::get_trading_volume(env.clone(), user.clone());
+        let key = DataKey::TradingVolume(user.clone());
+        env.storage().persistent().set(
+            &key,
+            &volume.checked_add(notional.0).ok_or(Error::MathOverflow)?,
+        );
+        Self::extend_persistent_ttl(env, &key);
+        Ok(())
+    }
+
+    // Helper function to split a collected fee between the insurance fund, the vault and the
+    // treasury, which also receives any rounding remainder
+    fn distribute_fee(env: &Env, amount: i128) -> Result<(), Error> {
+        if amount <= 0 {
+            return Ok(());
+        }
+        let config = Self::get_fee_config(env.clone());
+        let insurance_amount = fixed::mul_div(
+            amount,
+            config.insurance_share,
+            fixed::BPS_DENOMINATOR,
+            Rounding::Down,
+        )?;
+        let vault_amount = fixed::mul_div(
+            amount,
+            config.vault_share,
+            fixed::BPS_DENOMINATOR,
+            Rounding::Down,
+        )?;
+        let treasury_amount = amount - insurance_amount - vault_amount;
+
+        Self::credit_insurance_fund(env, insurance_amount);
+        Self::update_vault_balance(env, vault_amount);
+        if treasury_amount > 0 {
+            Self::token_client(env).transfer(
+                &env.current_contract_address(),
+                &Self::get_treasury(env.clone()),
+                &treasury_amount,
+            );
+        }
+        Ok(())
+    }
+
+    // Helper function to add or remove a position from the open interest of its side
+    fn update_open_interest(
+        env: &Env,
+        asset: &ReflectorAsset,
+        size: i128,
+        entry_notional: Usd,
+        increase: bool,
+        price: Price,
+    ) -> Result<(), Error> {
+        let mut open_interest = Self::get_open_interest(env.clone(), asset.clone());
+        let (size_delta, notional_delta) = if increase {
+            (size.abs(), entry_notional.0)
+        } else {
+            (-size.abs(), -entry_notional.0)
+        };
+        if size > 0 {
+            open_interest.long_size += size_delta;
+            open_interest.long_notional += notional_delta;
+        } else {
+            open_interest.short_size += size_delta;
+            open_interest.short_notional += notional_delta;
+        }
+        env.storage()
+            .instance()
+            .set(&DataKey::OpenInterest(asset.clone()), &open_interest);
+        env.storage().instance().set(
+            &DataKey::SettledPnl(asset.clone()),
+            &Self::get_open_interest_pnl(&open_interest, price)?.0,
+        );
+        Ok(())
+    }
+
+    // Helper function to get the net PnL of a market's open positions at a price, with long and
+    // short profits both rounded in favour of traders
+    fn get_open_interest_pnl(open_interest: &OpenInterest, price: Price) -> Result<Usd, Error> {
+        let long_value = Size(open_interest.long_size).notional(price, Rounding::Up)?;
+        let short_value = Size(open_interest.short_size).notional(price, Rounding::Down)?;
+        long_value
+            .checked_sub(Usd(open_interest.long_notional))?
+            .checked_add(Usd(open_interest.short_notional).checked_sub(short_value)?)
+    }
+
+    // Helper function to build a client for the collateral token
+    fn token_client(env: &Env) -> token::Client<'_> {
+        token::Client::new(env, &Self::get_collateral_token(env.clone()))
+    }
+
     // Helper function to get oracle address
     fn get_oracle_address(env: &Env) -> Result<Address, Error> {
         env.storage()
             .instance()
-            .get(&symbol_short!("oracle"))
+            .get(&DataKey::Oracle)
             .ok_or(Error::OracleError)
     }
 
-    // Helper function to check if position is liquidatable
-    fn is_position_liquidatable(env: &Env, position: &Position) -> Result<bool, Error> {
-        let oracle_address = Self::get_oracle_address(env)?;
-        let reflector_client = ReflectorClient::new(&env.clone(), &oracle_address);
+    // Helper function to close a position at the given price, settling PnL and funding against the
+    // collateral and the vault
+    fn settle_position(
+        env: &Env,
+        position: Position,
+        market: &MarketConfig,
+        current_price: Price,
+    ) -> Result<Settlement, Error> {
+        // A cross-margin position settles against the whole account balance plus what the account
+        // may owe against its other collateral. Both are taken out of the account, leaving the
+        // debt, and paid back through pay_out along with whatever is left.
+        let collateral = if position.cross_margin {
+            let mut account = Self::get_margin_account(env.clone(), position.user.clone());
+            let credit = Self::usd_to_collateral(env, Self::get_basket_value(env, &account)?)?;
+            let balance = account.balance + credit;
+            account.balance = -credit;
+            if let Some(index) = account.positions.first_index_of(position.id) {
+                account.positions.remove(index);
+            }
+            Self::save_margin_account(env, &position.user, &account);
+            balance
+        } else {
+            position.collateral
+        };
+
+        let settlement = Self::realize(
+            env,
+            &position,
+            market,
+            position.size,
+            collateral,
+            current_price,
+        )?;
+        let final_collateral = settlement.final_collateral;
+
+        // The vault is the counterparty: it pays out profits and collects losses. A loss beyond
+        // the collateral can only be collected from the insurance fund.
+        let vault_delta = if final_collateral < 0 {
+            collateral + Self::cover_bad_debt(env, -final_collateral)
+        } else {
+            collateral - final_collateral
+        };
+        Self::update_vault_balance(env, vault_delta);
+
+        // Mark the position closed and drop it from its owner's index; the closed record is no
+        // longer extended and is archived once its TTL lapses
+        let mut updated_position = position;
+        updated_position.is_open = false;
+        Self::save_position(env, &updated_position);
+
+        let mut user_positions =
+            Self::get_user_positions(env.clone(), updated_position.user.clone());
+        if let Some(index) = user_positions.first_index_of(updated_position.id) {
+            user_positions.remove(index);
+        }
+        Self::save_user_positions(env, &updated_position.user, &user_positions);
+
+        let net_key = DataKey::NetPosition(
+            updated_position.user.clone(),
+            updated_position.asset.clone(),
+        );
+        let net_position_id: Option<u64> = env.storage().persistent().get(&net_key);
+        if net_position_id == Some(updated_position.id) {
+            env.storage().persistent().remove(&net_key);
+        }
+        env.storage()
+            .persistent()
+            .remove(&DataKey::TrailingStop(updated_position.id));
+
+        // Update total open positions
+        let total_positions = Self::get_total_positions(env.clone()) - 1;
+        env.storage()
+            .instance()
+            .set(&DataKey::TotalPositions, &total_positions);
+
+        Ok(settlement)
+    }
+
+    // Helper function to realize PnL and accrued funding on `size` of a position backed by
+    // `collateral`, removing that size from open interest
+    fn realize(
+        env: &Env,
+        position: &Position,
+        market: &MarketConfig,
+        size: i128,
+        collateral: i128,
+        current_price: Price,
+    ) -> Result<Settlement, Error> {
+        // Calculate PnL
+        let entry_price = current_price.with_value(position.entry_price);
+        let pnl = Size(size).pnl(entry_price, current_price)?;
+
+        // Settle accrued funding against the collateral
+        Self::accrue_funding(env, market)?;
+        let mut realized = position.clone();
+        realized.size = size;
+        let funding_owed = Self::get_accrued_funding(env, &realized)?;
+        let entry_notional = Size(size).notional(entry_price, Rounding::Down)?;
+        Self::update_open_interest(
+            env,
+            &position.asset,
+            size,
+            entry_notional,
+            false,
+            current_price,
+        )?;
+
+        // Calculate final collateral, converting the USD settlement into collateral tokens
+        let settlement = Self::usd_to_collateral(env, pnl.checked_sub(funding_owed)?)?;
+        let final_collateral = collateral
+            .checked_add(settlement)
+            .ok_or(Error::MathOverflow)?;
+
+        Ok(Settlement {
+            pnl,
+            funding: funding_owed,
+            collateral,
+            final_collateral,
+        })
+    }
+
+    // Helper function to get position equity in USD: collateral value plus unrealized PnL minus accrued funding
+    fn get_position_equity(
+        env: &Env,
+        position: &Position,
+        current_price: Price,
+    ) -> Result<Usd, Error> {
+        let entry_price = current_price.with_value(position.entry_price);
+        let pnl = Size(position.size).pnl(entry_price, current_price)?;
+        let funding_owed = Self::get_accrued_funding(env, position)?;
+        Self::get_margin_value(env, position.collateral)?
+            .checked_add(pnl)?
+            .checked_sub(funding_owed)
+    }
+
+    // Helper function to check if position equity has fallen below the maintenance margin at the
+    // given (mark) price
+    fn is_position_liquidatable(
+        env: &Env,
+        position: &Position,
+        market: &MarketConfig,
+        current_price: Price,
+    ) -> Result<bool, Error> {
+        // Maintenance margin is a fraction of the current notional, rounded up
+        let maintenance_margin_required = Size(position.size)
+            .notional(current_price, Rounding::Up)?
+            .bps(market.maintenance_margin, Rounding::Up)?;
+
+        let equity = Self::get_position_equity(env, position, current_price)?;
+
+        Ok(equity < maintenance_margin_required)
+    }
+
+    // Helper function to check that a position's equity, excluding unrealized profit (which can not
+    // back the position before it is realized), covers the initial margin at the market's maximum
+    // leverage
+    fn check_initial_margin(
+        env: &Env,
+        position: &Position,
+        market: &MarketConfig,
+        current_price: Price,
+    ) -> Result<(), Error> {
+        let entry_price = current_price.with_value(position.entry_price);
+        let pnl = Size(position.size).pnl(entry_price, current_price)?;
+        let equity = Self::get_position_equity(env, position, current_price)?
+            .checked_sub(Usd(pnl.0.max(0)))?;
+        let initial_margin = Size(position.size)
+            .notional(current_price, Rounding::Up)?
+            .div(market.max_leverage as i128, Rounding::Up)?;
+        if equity < initial_margin {
+            return Err(Error::InsufficientCollateral);
+        }
+        Ok(())
+    }
+
+    // Helper function to absorb a loss in excess of position collateral. The insurance fund covers it
+    // first; anything it cannot cover is recorded as bad debt to be recovered by auto-deleveraging.
+    // Returns the amount covered by the insurance fund.
+    fn cover_bad_debt(env: &Env, deficit: i128) -> i128 {
+        let insurance_fund = Self::get_insurance_fund(env.clone());
+        let covered = deficit.min(insurance_fund);
+        env.storage()
+            .instance()
+            .set(&DataKey::InsuranceFund, &(insurance_fund - covered));
+
+        let mut bad_debt = Self::get_bad_debt(env.clone());
+        if deficit > covered {
+            bad_debt += deficit - covered;
+            env.storage().instance().set(&DataKey::BadDebt, &bad_debt);
+        }
 
-        let price_data = reflector_client
-            .lastprice(&position.asset)
-            .ok_or(Error::OracleError)?;
+        events::bad_debt(
+            env,
+            BadDebtEvent {
+                deficit,
+                covered,
+                outstanding: bad_debt,
+            },
+        );
+
+        covered
+    }
+
+    // Helper function to add a signed amount to the vault balance
+    fn update_vault_balance(env: &Env, delta: i128) {
+        let mut vault = Self::get_vault(env.clone());
+        vault.balance += delta;
+        env.storage().instance().set(&DataKey::Vault, &vault);
+    }
 
-        let current_price = price_data.price;
+    // Helper function to ensure the vault could pay out every profit it owed
+    fn check_vault_solvency(env: &Env) -> Result<(), Error> {
+        if Self::get_vault(env.clone()).balance < 0 {
+            return Err(Error::InsufficientLiquidity);
+        }
+        Ok(())
+    }
 
-        // Calculate current position value
-        let position_value = position.size.abs() * current_price / 10_000_000_000_000_000i128;
-        let required_margin = position_value / position.leverage as i128;
+    // Helper function to check that net trader exposure across all markets stays within the
+    // maximum utilization of a vault holding `vault_balance`
+    fn check_utilization(env: &Env, vault_balance: i128) -> Result<(), Error> {
+        let mut net_exposure = Usd(0);
+        for asset in Self::get_market_assets(env).iter() {
+            let open_interest = Self::get_open_interest(env.clone(), asset);
+            let net = (open_interest.long_notional - open_interest.short_notional).abs();
+            net_exposure = net_exposure.checked_add(Usd(net))?;
+        }
+        if net_exposure.0 == 0 {
+            return Ok(());
+        }
 
-        // Check if current collateral meets maintenance margin
-        let maintenance_margin_required =
-            required_margin * Self::get_maintenance_margin(env.clone().clone()) / 10_000i128;
+        let available = Self::get_collateral_value(env, vault_balance.max(0))?
+            .bps(Self::get_max_utilization(env.clone()), Rounding::Down)?;
+        if net_exposure > available {
+            return Err(Error::InsufficientLiquidity);
+        }
+        Ok(())
+    }
 
-        Ok(position.collateral < maintenance_margin_required)
+    // Helper function to add liquidation proceeds or fees to the insurance fund
+    fn credit_insurance_fund(env: &Env, amount: i128) {
+        if amount <= 0 {
+            return;
+        }
+        let balance = Self::get_insurance_fund(env.clone()) + amount;
+        env.storage()
+            .instance()
+            .set(&DataKey::InsuranceFund, &balance);
     }
 }
 
@@ -2,6 +2,7 @@ use soroban_sdk::{contracttype, Address, Env, Symbol, Vec};
 
 // Oracle contract interface exported as ReflectorClient
 #[soroban_sdk::contractclient(name = "ReflectorClient")]
+#[allow(dead_code)]
 pub trait Contract {
     // Base oracle symbol the price is reported in
     fn base(e: Env) -> Asset;
@@ -1,9 +1,22 @@
 #![cfg(test)]
 
-use super::{PerpetualFutures, PerpetualFuturesClient};
-use soroban_sdk::testutils::Address as TestAddress;
-use soroban_sdk::{contract, contractimpl, Address, Env, Symbol};
+use super::{
+    CollateralConfig, DataKey, Error, FeeConfig, FeeTier, MarkPriceFallback, MarketConfig,
+    OpenInterest, OracleConfig, OracleSource, PerpetualFutures, PerpetualFuturesClient,
+    PositionMode, QuoteAsset, TrailingDistance, Vault, POSITION_BUMP_AMOUNT,
+};
+use soroban_sdk::testutils::storage::Persistent;
+use soroban_sdk::testutils::{Address as TestAddress, Events, Ledger, MockAuth, MockAuthInvoke};
+use soroban_sdk::token::{StellarAssetClient, TokenClient};
+use soroban_sdk::{
+    contract, contractimpl, symbol_short, Address, Env, IntoVal, Symbol, TryFromVal, Val, Vec,
+};
 
+use super::events::{
+    CollateralChangedEvent, CollateralSeizedEvent, OrderExecutedEvent, PositionClosedEvent,
+    PositionOpenedEvent, TpSlTriggeredEvent,
+};
+use super::fixed::{self, Collateral, Price, Rounding, Size, Usd};
 use super::reflector::{Asset, PriceData};
 
 #[contract]
@@ -11,35 +24,234 @@ pub struct ReflectorMock;
 
 #[contractimpl]
 impl ReflectorMock {
+    pub fn set_price(env: Env, asset: Asset, price: i128) {
+        let mut assets = Self::assets(env.clone());
+        if !assets.contains(&asset) {
+            assets.push_back(asset.clone());
+            env.storage()
+                .instance()
+                .set(&symbol_short!("assets"), &assets);
+        }
+        env.storage().instance().set(&asset, &price);
+    }
+
+    // Assets with a price set by the test, besides the ones with a default price
+    pub fn assets(env: Env) -> Vec<Asset> {
+        env.storage()
+            .instance()
+            .get(&symbol_short!("assets"))
+            .unwrap_or_else(|| {
+                soroban_sdk::vec![
+                    &env,
+                    Asset::Other(Symbol::new(&env, "BTC")),
+                    Asset::Other(Symbol::new(&env, "ETH")),
+                    Asset::Other(Symbol::new(&env, "XLM")),
+                ]
+            })
+    }
+
+    // Report prices as last updated at `timestamp` instead of the current ledger time
+    pub fn set_timestamp(env: Env, timestamp: u64) {
+        env.storage()
+            .instance()
+            .set(&symbol_short!("ts"), &timestamp);
+    }
+
+    // Stop reporting a price for an asset
+    pub fn set_unavailable(env: Env, asset: Asset) {
+        env.storage()
+            .instance()
+            .set(&(symbol_short!("none"), asset), &true);
+    }
+
+    pub fn set_decimals(env: Env, decimals: u32) {
+        env.storage()
+            .instance()
+            .set(&symbol_short!("decimals"), &decimals);
+    }
+
     pub fn lastprice(env: Env, asset: Asset) -> Option<PriceData> {
-        // Return a mock price for BTC
-        let price = match asset {
-            Asset::Other(symbol) if symbol == Symbol::new(&env, "BTC") => {
-                50_000_000_000_000i128 // $50,000 with 14 decimals
-            }
-            _ => 1_000_000_000_000i128, // Default $1,000 with 14 decimals
-        };
+        if env
+            .storage()
+            .instance()
+            .has(&(symbol_short!("none"), asset.clone()))
+        {
+            return None;
+        }
+
+        // Return the price set by the test, or a default mock price
+        let price = env
+            .storage()
+            .instance()
+            .get(&asset)
+            .unwrap_or_else(|| match asset {
+                Asset::Other(symbol) if symbol == Symbol::new(&env, "BTC") => {
+                    5_000_000_000_000_000_000i128 // $50,000 with 14 decimals
+                }
+                Asset::Other(symbol) if symbol == Symbol::new(&env, "XLM") => {
+                    10_000_000_000_000i128 // $0.10 with 14 decimals
+                }
+                _ => 100_000_000_000_000_000i128, // Default $1,000 with 14 decimals
+            });
 
         Some(PriceData {
             price,
-            timestamp: env.ledger().timestamp(),
+            timestamp: env
+                .storage()
+                .instance()
+                .get(&symbol_short!("ts"))
+                .unwrap_or(env.ledger().timestamp()),
+        })
+    }
+
+    // Cross price derived from both assets' prices, like the oracle does
+    pub fn x_last_price(env: Env, base_asset: Asset, quote_asset: Asset) -> Option<PriceData> {
+        let base = Self::lastprice(env.clone(), base_asset)?;
+        let quote = Self::lastprice(env.clone(), quote_asset)?;
+        Some(PriceData {
+            price: base.price * 10i128.pow(Self::decimals(env)) / quote.price,
+            timestamp: base.timestamp,
         })
     }
 
-    pub fn decimals() -> u32 {
-        14
+    pub fn decimals(env: Env) -> u32 {
+        env.storage()
+            .instance()
+            .get(&symbol_short!("decimals"))
+            .unwrap_or(14)
+    }
+
+    pub fn set_resolution(env: Env, resolution: u32) {
+        env.storage()
+            .instance()
+            .set(&symbol_short!("res"), &resolution);
+    }
+
+    pub fn resolution(env: Env) -> u32 {
+        env.storage()
+            .instance()
+            .get(&symbol_short!("res"))
+            .unwrap_or(300)
+    }
+
+    // Set the time-weighted average returned for an asset; None makes it unavailable
+    pub fn set_twap(env: Env, asset: Asset, twap: Option<i128>) {
+        env.storage()
+            .instance()
+            .set(&(symbol_short!("twap"), asset), &twap);
+    }
+
+    pub fn twap(env: Env, asset: Asset, _records: u32) -> Option<i128> {
+        // Return the average set by the test, or the latest price
+        env.storage()
+            .instance()
+            .get(&(symbol_short!("twap"), asset.clone()))
+            .unwrap_or_else(|| Self::lastprice(env, asset).map(|price_data| price_data.price))
+    }
+
+    pub fn x_twap(env: Env, base_asset: Asset, quote_asset: Asset, records: u32) -> Option<i128> {
+        let base = Self::twap(env.clone(), base_asset, records)?;
+        let quote = Self::twap(env.clone(), quote_asset, records)?;
+        Some(base * 10i128.pow(Self::decimals(env)) / quote)
+    }
+}
+
+fn btc_market(env: &Env, oracle: &Address) -> MarketConfig {
+    MarketConfig {
+        asset: Asset::Other(Symbol::new(env, "BTC")),
+        quote_asset: QuoteAsset::Usd,
+        min_collateral: 100_000_000i128,
+        max_leverage: 10,
+        maintenance_margin: 500i128,
+        funding_rate_interval: 3600u64,
+        max_funding_rate: 1_000_000i128, // 0.1% per interval
+        liquidation_fee: 100i128,        // 1%
+        open_fee: 0i128,
+        close_fee: 0i128,
+        position_mode: PositionMode::Hedge,
+        oracle_sources: soroban_sdk::vec![env, oracle.clone()],
+        oracle_quorum: 1,
+        is_active: true,
+        mark_price_records: 0,
+        mark_price_fallback: MarkPriceFallback::Spot,
+    }
+}
+
+// Mock oracle at the Reflector testnet address, which initialize configures and discovers
+fn register_reflector(env: &Env) -> Address {
+    let address = Address::from_str(
+        env,
+        "CCYOZJCOPG34LLQQ7N24YXBM7LL62R7ONMZ3G6WZAAYPB5OYKOMJRN63",
+    );
+    env.register_at(&address, ReflectorMock, ())
+}
+
+// The settlement token, priced as XLM on the oracle
+fn xlm_collateral(env: &Env, token: &Address) -> CollateralConfig {
+    CollateralConfig {
+        token: token.clone(),
+        asset: Asset::Other(Symbol::new(env, "XLM")),
+        haircut: 0,
+        is_active: true,
     }
 }
 
+// Initialized contract with a funded user and a BTC market backed by the mock oracle
+fn setup_without_liquidity(env: &Env) -> (PerpetualFuturesClient<'_>, Address, Address) {
+    env.mock_all_auths();
+
+    let contract_id = env.register(PerpetualFutures, ());
+    let client = PerpetualFuturesClient::new(env, &contract_id);
+    let reflector_id = register_reflector(env);
+
+    let admin = <soroban_sdk::Address as TestAddress>::generate(env);
+    let treasury = <soroban_sdk::Address as TestAddress>::generate(env);
+    let user = <soroban_sdk::Address as TestAddress>::generate(env);
+    let token = env
+        .register_stellar_asset_contract_v2(admin.clone())
+        .address();
+    StellarAssetClient::new(env, &token).mint(&user, &1_000_000_000_000i128);
+
+    client.initialize(
+        &admin,
+        &treasury,
+        &xlm_collateral(env, &token),
+        &100_000_000i128,
+        &10,
+        &500i128,
+        &3600u64,
+    );
+    client.add_market(&btc_market(env, &reflector_id));
+
+    (client, user, reflector_id)
+}
+
+// Initialized contract as above, with 1,000,000 XLM ($100,000) of vault liquidity
+fn setup(env: &Env) -> (PerpetualFuturesClient<'_>, Address, Address) {
+    let (client, user, reflector_id) = setup_without_liquidity(env);
+
+    let provider = <soroban_sdk::Address as TestAddress>::generate(env);
+    StellarAssetClient::new(env, &client.get_collateral_token())
+        .mint(&provider, &10_000_000_000_000i128);
+    client.deposit_liquidity(&provider, &10_000_000_000_000i128);
+
+    (client, user, reflector_id)
+}
+
 #[test]
 fn test_initialize() {
     let env = Env::default();
-    let contract_id = env.register_contract(None, PerpetualFutures);
+    env.mock_all_auths();
+    let contract_id = env.register(PerpetualFutures, ());
     let client = PerpetualFuturesClient::new(&env, &contract_id);
+    register_reflector(&env);
 
     let admin = <soroban_sdk::Address as TestAddress>::generate(&env);
     let treasury = <soroban_sdk::Address as TestAddress>::generate(&env);
-    let min_collateral = 100_000_000i128; // 100 USDC (6 decimals)
+    let token = env
+        .register_stellar_asset_contract_v2(admin.clone())
+        .address();
+    let min_collateral = 100_000_000i128; // 10 XLM (7 decimals)
     let max_leverage = 10;
     let maintenance_margin = 500i128; // 5%
     let funding_rate_interval = 3600u64; // 1 hour
@@ -47,6 +259,7 @@ fn test_initialize() {
     client.initialize(
         &admin,
         &treasury,
+        &xlm_collateral(&env, &token),
         &min_collateral,
         &max_leverage,
         &maintenance_margin,
@@ -59,14 +272,147 @@ fn test_initialize() {
     assert_eq!(client.get_maintenance_margin(), maintenance_margin);
 }
 
+#[test]
+fn test_initialize_requires_admin_auth() {
+    let env = Env::default();
+    let contract_id = env.register(PerpetualFutures, ());
+    let client = PerpetualFuturesClient::new(&env, &contract_id);
+    register_reflector(&env);
+
+    let admin = <soroban_sdk::Address as TestAddress>::generate(&env);
+    let attacker = <soroban_sdk::Address as TestAddress>::generate(&env);
+    let treasury = <soroban_sdk::Address as TestAddress>::generate(&env);
+    let token = env
+        .register_stellar_asset_contract_v2(admin.clone())
+        .address();
+    let collateral = xlm_collateral(&env, &token);
+    let args = (
+        admin.clone(),
+        treasury.clone(),
+        collateral.clone(),
+        100_000_000i128,
+        10u32,
+        500i128,
+        3600u64,
+    );
+
+    // Only the attacker signs, so the admin check fails and the contract stays uninitialized
+    env.mock_auths(&[MockAuth {
+        address: &attacker,
+        invoke: &MockAuthInvoke {
+            contract: &contract_id,
+            fn_name: "initialize",
+            args: args.clone().into_val(&env),
+            sub_invokes: &[],
+        },
+    }]);
+    let result = client.try_initialize(
+        &admin,
+        &treasury,
+        &collateral,
+        &100_000_000i128,
+        &10,
+        &500i128,
+        &3600u64,
+    );
+    assert!(result.is_err());
+    assert!(client.try_get_admin().is_err());
+
+    // The admin's own signature is accepted
+    env.mock_auths(&[MockAuth {
+        address: &admin,
+        invoke: &MockAuthInvoke {
+            contract: &contract_id,
+            fn_name: "initialize",
+            args: args.into_val(&env),
+            sub_invokes: &[],
+        },
+    }]);
+    client.initialize(
+        &admin,
+        &treasury,
+        &collateral,
+        &100_000_000i128,
+        &10,
+        &500i128,
+        &3600u64,
+    );
+    assert_eq!(client.get_admin(), admin);
+}
+
+#[test]
+fn test_initialize_discovers_oracle() {
+    let env = Env::default();
+    env.mock_all_auths();
+    let contract_id = env.register(PerpetualFutures, ());
+    let client = PerpetualFuturesClient::new(&env, &contract_id);
+    let reflector_id = register_reflector(&env);
+
+    let admin = <soroban_sdk::Address as TestAddress>::generate(&env);
+    let treasury = <soroban_sdk::Address as TestAddress>::generate(&env);
+    let user = <soroban_sdk::Address as TestAddress>::generate(&env);
+    let token = env
+        .register_stellar_asset_contract_v2(admin.clone())
+        .address();
+    StellarAssetClient::new(&env, &token).mint(&user, &1_000_000_000_000i128);
+
+    // The oracle must quote the settlement token
+    let mut collateral = xlm_collateral(&env, &token);
+    collateral.asset = Asset::Other(Symbol::new(&env, "USDC"));
+    let result = client.try_initialize(
+        &admin,
+        &treasury,
+        &collateral,
+        &100_000_000i128,
+        &10,
+        &500i128,
+        &3600u64,
+    );
+    assert_eq!(result, Err(Ok(Error::InvalidAsset)));
+
+    client.initialize(
+        &admin,
+        &treasury,
+        &xlm_collateral(&env, &token),
+        &100_000_000i128,
+        &10,
+        &500i128,
+        &3600u64,
+    );
+    assert_eq!(
+        client.get_oracle_source(&reflector_id),
+        OracleSource {
+            decimals: 14,
+            resolution: 300,
+        }
+    );
+
+    // A market is priced without setting the oracle again
+    client.add_market(&btc_market(&env, &reflector_id));
+    StellarAssetClient::new(&env, &token).mint(&admin, &10_000_000_000_000i128);
+    client.deposit_liquidity(&admin, &10_000_000_000_000i128);
+    let btc_asset = Asset::Other(Symbol::new(&env, "BTC"));
+    let position_id =
+        client.open_position(&user, &btc_asset, &1_000_000i128, &5, &100_000_000_000i128);
+    assert_eq!(
+        client.get_position(&position_id).entry_price,
+        5_000_000_000_000_000_000
+    );
+}
+
 #[test]
 fn test_initialize_already_initialized() {
     let env = Env::default();
-    let contract_id = env.register_contract(None, PerpetualFutures);
+    env.mock_all_auths();
+    let contract_id = env.register(PerpetualFutures, ());
     let client = PerpetualFuturesClient::new(&env, &contract_id);
+    register_reflector(&env);
 
     let admin = <soroban_sdk::Address as TestAddress>::generate(&env);
     let treasury = <soroban_sdk::Address as TestAddress>::generate(&env);
+    let token = env
+        .register_stellar_asset_contract_v2(admin.clone())
+        .address();
     let min_collateral = 100_000_000i128;
     let max_leverage = 10;
     let maintenance_margin = 500i128;
@@ -75,6 +421,7 @@ fn test_initialize_already_initialized() {
     client.initialize(
         &admin,
         &treasury,
+        &xlm_collateral(&env, &token),
         &min_collateral,
         &max_leverage,
         &maintenance_margin,
@@ -85,6 +432,7 @@ fn test_initialize_already_initialized() {
     let result = client.try_initialize(
         &admin,
         &treasury,
+        &xlm_collateral(&env, &token),
         &min_collateral,
         &max_leverage,
         &maintenance_margin,
@@ -97,15 +445,22 @@ fn test_initialize_already_initialized() {
 #[test]
 fn test_open_position() {
     let env = Env::default();
-    let contract_id = env.register_contract(None, PerpetualFutures);
+    env.mock_all_auths();
+    let contract_id = env.register(PerpetualFutures, ());
     let client = PerpetualFuturesClient::new(&env, &contract_id);
 
-    let reflector_id = env.register_contract(None, ReflectorMock);
+    let reflector_id = register_reflector(&env);
     let reflector_client = ReflectorMockClient::new(&env, &reflector_id);
 
     let admin = <soroban_sdk::Address as TestAddress>::generate(&env);
     let treasury = <soroban_sdk::Address as TestAddress>::generate(&env);
-    let min_collateral = 100_000_000i128; // 100 USDC (6 decimals)
+    let user = <soroban_sdk::Address as TestAddress>::generate(&env);
+    let token = env
+        .register_stellar_asset_contract_v2(admin.clone())
+        .address();
+    let token_client = TokenClient::new(&env, &token);
+    StellarAssetClient::new(&env, &token).mint(&user, &1_000_000_000_000i128);
+    let min_collateral = 100_000_000i128; // 10 XLM (7 decimals)
     let max_leverage = 10;
     let maintenance_margin = 500i128; // 5%
     let funding_rate_interval = 3600u64; // 1 hour
@@ -114,41 +469,61 @@ fn test_open_position() {
     client.initialize(
         &admin,
         &treasury,
+        &xlm_collateral(&env, &token),
         &min_collateral,
         &max_leverage,
         &maintenance_margin,
         &funding_rate_interval,
     );
 
-    // Set the oracle address
+    // Set the oracle address and list the BTC market
     client.set_oracle(&reflector_id);
+    client.add_market(&btc_market(&env, &reflector_id));
+
+    // Provide vault liquidity to take the other side
+    let provider = <soroban_sdk::Address as TestAddress>::generate(&env);
+    StellarAssetClient::new(&env, &token).mint(&provider, &10_000_000_000_000i128);
+    client.deposit_liquidity(&provider, &10_000_000_000_000i128);
 
     // Set a price for BTC
     let btc_asset = Asset::Other(Symbol::new(&env, "BTC"));
     reflector_client.lastprice(&btc_asset);
 
     // Test opening a position with BTC
-    let btc_symbol = Symbol::new(&env, "BTC");
-    let size = 1_000_000i128; // 1 BTC (6 decimals)
-    let leverage = 2;
-    let collateral = 200_000_000i128; // 200 USDC
+    let size = 1_000_000i128; // 0.1 BTC (7 decimals), $5,000 notional
+    let leverage = 5;
+    let collateral = 100_000_000_000i128; // 10,000 XLM, $1,000
 
-    let result = client.try_open_position(&btc_symbol, &size, &leverage, &collateral);
+    let result = client.try_open_position(&user, &btc_asset, &size, &leverage, &collateral);
     assert!(result.is_ok());
+
+    // Collateral is escrowed by the contract
+    assert_eq!(token_client.balance(&user), 900_000_000_000i128);
+    assert_eq!(
+        token_client.balance(&contract_id),
+        10_000_000_000_000i128 + collateral
+    );
 }
 
 #[test]
 fn test_close_position() {
     let env = Env::default();
-    let contract_id = env.register_contract(None, PerpetualFutures);
+    env.mock_all_auths();
+    let contract_id = env.register(PerpetualFutures, ());
     let client = PerpetualFuturesClient::new(&env, &contract_id);
 
-    let reflector_id = env.register_contract(None, ReflectorMock);
+    let reflector_id = register_reflector(&env);
     let reflector_client = ReflectorMockClient::new(&env, &reflector_id);
 
     let admin = <soroban_sdk::Address as TestAddress>::generate(&env);
     let treasury = <soroban_sdk::Address as TestAddress>::generate(&env);
-    let min_collateral = 100_000_000i128; // 100 USDC (6 decimals)
+    let user = <soroban_sdk::Address as TestAddress>::generate(&env);
+    let token = env
+        .register_stellar_asset_contract_v2(admin.clone())
+        .address();
+    let token_client = TokenClient::new(&env, &token);
+    StellarAssetClient::new(&env, &token).mint(&user, &1_000_000_000_000i128);
+    let min_collateral = 100_000_000i128; // 10 XLM (7 decimals)
     let max_leverage = 10;
     let maintenance_margin = 500i128; // 5%
     let funding_rate_interval = 3600u64; // 1 hour
@@ -157,27 +532,1865 @@ fn test_close_position() {
     client.initialize(
         &admin,
         &treasury,
+        &xlm_collateral(&env, &token),
         &min_collateral,
         &max_leverage,
         &maintenance_margin,
         &funding_rate_interval,
     );
 
-    // Set the oracle address
+    // Set the oracle address and list the BTC market
     client.set_oracle(&reflector_id);
+    client.add_market(&btc_market(&env, &reflector_id));
+
+    // Provide vault liquidity to take the other side
+    let provider = <soroban_sdk::Address as TestAddress>::generate(&env);
+    StellarAssetClient::new(&env, &token).mint(&provider, &10_000_000_000_000i128);
+    client.deposit_liquidity(&provider, &10_000_000_000_000i128);
 
     // Set a price for BTC
     let btc_asset = Asset::Other(Symbol::new(&env, "BTC"));
     reflector_client.lastprice(&btc_asset);
 
     // Test opening a position with BTC
-    let btc_symbol = Symbol::new(&env, "BTC");
-    let size = 1_000_000i128; // 1 BTC (6 decimals)
-    let leverage = 2;
-    let collateral = 200_000_000i128; // 200 USDC
+    let size = 1_000_000i128; // 0.1 BTC (7 decimals), $5,000 notional
+    let leverage = 5;
+    let collateral = 100_000_000_000i128; // 10,000 XLM, $1,000
 
-    let position_id = client.open_position(&btc_symbol, &size, &leverage, &collateral);
+    let position_id = client.open_position(&user, &btc_asset, &size, &leverage, &collateral);
+    assert_eq!(client.get_user_positions(&user).len(), 1);
 
-    let result = client.try_close_position(&position_id);
+    let result = client.try_close_position(&user, &position_id);
     assert!(result.is_ok());
+
+    // The closed position is kept but no longer listed for the user
+    assert!(!client.get_position(&position_id).is_open);
+    assert_eq!(client.get_user_positions(&user).len(), 0);
+
+    // Price is unchanged, so the full collateral is paid back
+    assert_eq!(token_client.balance(&user), 1_000_000_000_000i128);
+    assert_eq!(token_client.balance(&contract_id), 10_000_000_000_000i128);
+}
+
+#[test]
+fn test_add_market() {
+    let env = Env::default();
+    let (client, _, reflector_id) = setup(&env);
+
+    let markets = client.list_markets();
+    assert_eq!(markets.len(), 1);
+    assert_eq!(markets.get(0).unwrap(), btc_market(&env, &reflector_id));
+
+    // Listing the same asset twice is rejected
+    let result = client.try_add_market(&btc_market(&env, &reflector_id));
+    assert_eq!(result, Err(Ok(Error::MarketAlreadyExists)));
+
+    // Market limits must stay within the protocol-wide bounds
+    let mut eth_market = btc_market(&env, &reflector_id);
+    eth_market.asset = Asset::Other(Symbol::new(&env, "ETH"));
+    eth_market.max_leverage = 50;
+    let result = client.try_add_market(&eth_market);
+    assert_eq!(result, Err(Ok(Error::InvalidLeverage)));
+}
+
+#[test]
+fn test_update_market() {
+    let env = Env::default();
+    let (client, _, reflector_id) = setup(&env);
+
+    let mut market = btc_market(&env, &reflector_id);
+    market.max_leverage = 5;
+    client.update_market(&market);
+    assert_eq!(client.get_market(&market.asset), market);
+
+    // Unknown markets cannot be updated
+    market.asset = Asset::Other(Symbol::new(&env, "ETH"));
+    let result = client.try_update_market(&market);
+    assert_eq!(result, Err(Ok(Error::InvalidAsset)));
+}
+
+#[test]
+fn test_update_market_with_open_interest() {
+    let env = Env::default();
+    let (client, user, reflector_id) = setup(&env);
+    let btc_asset = Asset::Other(Symbol::new(&env, "BTC"));
+    let reflector_client = ReflectorMockClient::new(&env, &reflector_id);
+    let position_id =
+        client.open_position(&user, &btc_asset, &1_000_000i128, &5, &100_000_000_000i128);
+
+    // Risk parameters can still be changed
+    let mut market = btc_market(&env, &reflector_id);
+    market.max_leverage = 5;
+    client.update_market(&market);
+
+    // The quote asset and oracles the open position was priced with can not, nor the position
+    // mode it was netted under
+    let mut changed = market.clone();
+    changed.quote_asset = QuoteAsset::Asset(Asset::Other(Symbol::new(&env, "XLM")));
+    let result = client.try_update_market(&changed);
+    assert_eq!(result, Err(Ok(Error::MarketHasOpenInterest)));
+
+    let mut changed = market.clone();
+    changed.position_mode = PositionMode::OneWay;
+    let result = client.try_update_market(&changed);
+    assert_eq!(result, Err(Ok(Error::MarketHasOpenInterest)));
+
+    let other_oracle = env.register(ReflectorMock, ());
+    let mut changed = market.clone();
+    changed.oracle_sources = soroban_sdk::vec![&env, other_oracle];
+    let result = client.try_update_market(&changed);
+    assert_eq!(result, Err(Ok(Error::MarketHasOpenInterest)));
+
+    // Nor can the oracle's decimals be rediscovered
+    reflector_client.set_decimals(&8);
+    let result = client.try_update_market(&market);
+    assert_eq!(result, Err(Ok(Error::MarketHasOpenInterest)));
+    assert_eq!(client.get_oracle_source(&reflector_id).decimals, 14);
+    reflector_client.set_decimals(&14);
+
+    // Once the position is closed the market can be reconfigured
+    client.close_position(&user, &position_id);
+    market.position_mode = PositionMode::OneWay;
+    client.update_market(&market);
+    assert_eq!(client.get_market(&btc_asset), market);
+}
+
+#[test]
+fn test_open_position_unknown_market() {
+    let env = Env::default();
+    let (client, user, _) = setup(&env);
+
+    let eth_asset = Asset::Other(Symbol::new(&env, "ETH"));
+    let result =
+        client.try_open_position(&user, &eth_asset, &1_000_000i128, &5, &100_000_000_000i128);
+    assert_eq!(result, Err(Ok(Error::InvalidAsset)));
+}
+
+#[test]
+fn test_open_position_paused_market() {
+    let env = Env::default();
+    let (client, user, _) = setup(&env);
+
+    let btc_asset = Asset::Other(Symbol::new(&env, "BTC"));
+    let position_id =
+        client.open_position(&user, &btc_asset, &1_000_000i128, &5, &100_000_000_000i128);
+
+    client.pause_market(&btc_asset);
+    assert!(!client.get_market(&btc_asset).is_active);
+
+    let result =
+        client.try_open_position(&user, &btc_asset, &1_000_000i128, &5, &100_000_000_000i128);
+    assert_eq!(result, Err(Ok(Error::InvalidAsset)));
+
+    // Existing positions can still be closed
+    client.close_position(&user, &position_id);
+}
+
+#[test]
+fn test_stellar_asset_market() {
+    let env = Env::default();
+    let (client, user, reflector_id) = setup(&env);
+    let token_client = TokenClient::new(&env, &client.get_collateral_token());
+
+    // A Stellar token tracked by the oracle by its contract address, listed at $0.50
+    let issuer = <soroban_sdk::Address as TestAddress>::generate(&env);
+    let aqua_asset = Asset::Stellar(env.register_stellar_asset_contract_v2(issuer).address());
+    let reflector_client = ReflectorMockClient::new(&env, &reflector_id);
+    reflector_client.set_price(&aqua_asset, &50_000_000_000_000i128);
+    let mut market = btc_market(&env, &reflector_id);
+    market.asset = aqua_asset.clone();
+    client.add_market(&market);
+
+    // 10,000 AQUA ($5,000) long with 10,000 XLM ($1,000) of collateral
+    let initial_balance = token_client.balance(&user);
+    let position_id = client.open_position(
+        &user,
+        &aqua_asset,
+        &100_000_000_000i128,
+        &5,
+        &100_000_000_000i128,
+    );
+    let position = client.get_position(&position_id);
+    assert_eq!(position.asset, aqua_asset);
+    assert_eq!(position.entry_price, 50_000_000_000_000);
+
+    // Up to $0.55: $500 profit, or 5,000 XLM
+    reflector_client.set_price(&aqua_asset, &55_000_000_000_000i128);
+    client.close_position(&user, &position_id);
+    assert_eq!(
+        token_client.balance(&user),
+        initial_balance + 50_000_000_000
+    );
+}
+
+#[test]
+fn test_funding_accrual() {
+    let env = Env::default();
+    let (client, user, _) = setup(&env);
+    let token_client = TokenClient::new(&env, &client.get_collateral_token());
+
+    let btc_asset = Asset::Other(Symbol::new(&env, "BTC"));
+    let position_id =
+        client.open_position(&user, &btc_asset, &1_000_000i128, &5, &100_000_000_000i128);
+    assert_eq!(
+        client.get_open_interest(&btc_asset),
+        OpenInterest {
+            long_size: 1_000_000,
+            short_size: 0,
+            long_notional: 50_000_000_000,
+            short_notional: 0,
+        }
+    );
+
+    // Nothing accrues before a full interval has elapsed
+    env.ledger().with_mut(|li| li.timestamp = 3_599);
+    assert_eq!(client.update_funding(&btc_asset).cumulative_index, 0);
+
+    // Only longs are open, so they pay the maximum rate for both elapsed intervals
+    env.ledger().with_mut(|li| li.timestamp = 7_300);
+    // 0.1% of the $50,000 mark price per BTC for each interval
+    let funding = client.update_funding(&btc_asset);
+    assert_eq!(funding.rate, 1_000_000);
+    assert_eq!(funding.cumulative_index, 2_000_000 * 500_000_000_000);
+    assert_eq!(funding.timestamp, 7_200);
+
+    // Funding owed ($10 on $5,000 entry notional) is deducted from the collateral paid back on close
+    client.close_position(&user, &position_id);
+    assert_eq!(
+        token_client.balance(&user),
+        1_000_000_000_000i128 - 1_000_000_000
+    );
+    assert_eq!(
+        client.get_open_interest(&btc_asset),
+        OpenInterest {
+            long_size: 0,
+            short_size: 0,
+            long_notional: 0,
+            short_notional: 0,
+        }
+    );
+}
+
+#[test]
+fn test_funding_balanced_open_interest() {
+    let env = Env::default();
+    let (client, user, _) = setup(&env);
+
+    let btc_asset = Asset::Other(Symbol::new(&env, "BTC"));
+    client.open_position(&user, &btc_asset, &1_000_000i128, &5, &100_000_000_000i128);
+    client.open_position(&user, &btc_asset, &-1_000_000i128, &5, &100_000_000_000i128);
+
+    env.ledger().with_mut(|li| li.timestamp = 7_200);
+    let funding = client.update_funding(&btc_asset);
+    assert_eq!(funding.rate, 0);
+    assert_eq!(funding.cumulative_index, 0);
+}
+
+#[test]
+fn test_open_position_insufficient_margin() {
+    let env = Env::default();
+    let (client, user, _) = setup(&env);
+
+    // $1,000 of XLM cannot back $5,000 of BTC at 2x leverage
+    let btc_asset = Asset::Other(Symbol::new(&env, "BTC"));
+    let result =
+        client.try_open_position(&user, &btc_asset, &1_000_000i128, &2, &100_000_000_000i128);
+    assert_eq!(result, Err(Ok(Error::InsufficientCollateral)));
+}
+
+#[test]
+fn test_close_position_with_profit() {
+    let env = Env::default();
+    let (client, user, reflector_id) = setup(&env);
+    let token_client = TokenClient::new(&env, &client.get_collateral_token());
+
+    let btc_asset = Asset::Other(Symbol::new(&env, "BTC"));
+    let position_id =
+        client.open_position(&user, &btc_asset, &1_000_000i128, &5, &100_000_000_000i128);
+
+    // BTC rallies from $50,000 to $55,000: 0.1 BTC earns $500, paid as 5,000 XLM
+    ReflectorMockClient::new(&env, &reflector_id)
+        .set_price(&btc_asset, &5_500_000_000_000_000_000i128);
+    client.close_position(&user, &position_id);
+
+    assert_eq!(
+        token_client.balance(&user),
+        1_000_000_000_000i128 + 50_000_000_000
+    );
+
+    // The profit is paid by the vault
+    assert_eq!(
+        client.get_vault().balance,
+        10_000_000_000_000i128 - 50_000_000_000
+    );
+}
+
+#[test]
+fn test_fixed_point_math() {
+    // Rounding direction is explicit for both signs
+    assert_eq!(fixed::mul_div(7, 1, 2, Rounding::Down), Ok(3));
+    assert_eq!(fixed::mul_div(7, 1, 2, Rounding::Up), Ok(4));
+    assert_eq!(fixed::mul_div(-7, 1, 2, Rounding::Down), Ok(-4));
+    assert_eq!(fixed::mul_div(-7, 1, 2, Rounding::Up), Ok(-3));
+    assert_eq!(
+        fixed::mul_div(i128::MAX, 2, 1, Rounding::Down),
+        Err(Error::MathOverflow)
+    );
+    assert_eq!(
+        fixed::mul_div(1, 1, 0, Rounding::Down),
+        Err(Error::MathOverflow)
+    );
+
+    // 0.1 BTC at $50,000 (14 decimal price) is $5,000 (7 decimals)
+    let btc_price = Price::new(5_000_000_000_000_000_000, 14);
+    assert_eq!(
+        Size(1_000_000).notional(btc_price, Rounding::Down),
+        Ok(Usd(50_000_000_000))
+    );
+
+    // A short loses when the price rises
+    let exit_price = Price::new(5_100_000_000_000_000_000, 14);
+    assert_eq!(
+        Size(-1_000_000).pnl(btc_price, exit_price),
+        Ok(Usd(-1_000_000_000))
+    );
+
+    // 10 XLM at $0.10 is $1, and back
+    let xlm_price = Price::new(10_000_000_000_000, 14);
+    let xlm = Collateral::new(100_000_000, 7);
+    assert_eq!(xlm.value(xlm_price, Rounding::Down), Ok(Usd(10_000_000)));
+    assert_eq!(
+        Collateral::from_value(Usd(10_000_000), 7, xlm_price, Rounding::Down),
+        Ok(xlm)
+    );
+}
+
+#[test]
+fn test_liquidate_position() {
+    let env = Env::default();
+    let (client, user, reflector_id) = setup(&env);
+    let token_client = TokenClient::new(&env, &client.get_collateral_token());
+    let keeper = <soroban_sdk::Address as TestAddress>::generate(&env);
+
+    let btc_asset = Asset::Other(Symbol::new(&env, "BTC"));
+    let position_id =
+        client.open_position(&user, &btc_asset, &1_000_000i128, &5, &100_000_000_000i128);
+
+    // At $45,000 equity is $500 against a $225 maintenance margin
+    let reflector_client = ReflectorMockClient::new(&env, &reflector_id);
+    reflector_client.set_price(&btc_asset, &4_500_000_000_000_000_000i128);
+    let result = client.try_liquidate_position(&keeper, &position_id);
+    assert_eq!(result, Err(Ok(Error::MaintenanceMarginNotMet)));
+
+    // At $42,000 equity is $200 against a $210 maintenance margin
+    reflector_client.set_price(&btc_asset, &4_200_000_000_000_000_000i128);
+    client.liquidate_position(&keeper, &position_id);
+    assert!(!client.get_position(&position_id).is_open);
+
+    // Keeper earns 1% of the $4,200 notional (420 XLM), the rest of the 2,000 XLM left is insurance
+    assert_eq!(token_client.balance(&keeper), 4_200_000_000i128);
+    assert_eq!(client.get_insurance_fund(), 15_800_000_000i128);
+    assert_eq!(token_client.balance(&user), 900_000_000_000i128);
+
+    // The vault collects the 8,000 XLM loss
+    assert_eq!(
+        client.get_vault().balance,
+        10_000_000_000_000i128 + 80_000_000_000
+    );
+}
+
+#[test]
+fn test_bad_debt_covered_by_insurance_fund() {
+    let env = Env::default();
+    let (client, user, reflector_id) = setup(&env);
+    let token_client = TokenClient::new(&env, &client.get_collateral_token());
+    let keeper = <soroban_sdk::Address as TestAddress>::generate(&env);
+
+    client.deposit_insurance_fund(&user, &100_000_000_000i128);
+    assert_eq!(client.get_insurance_fund(), 100_000_000_000i128);
+
+    let btc_asset = Asset::Other(Symbol::new(&env, "BTC"));
+    let position_id =
+        client.open_position(&user, &btc_asset, &1_000_000i128, &5, &100_000_000_000i128);
+
+    // A crash to $35,000 loses $1,500 on $1,000 of collateral
+    ReflectorMockClient::new(&env, &reflector_id)
+        .set_price(&btc_asset, &3_500_000_000_000_000_000i128);
+    client.liquidate_position(&keeper, &position_id);
+
+    // The 5,000 XLM shortfall is paid by the insurance fund and nothing is left for the keeper
+    assert_eq!(client.get_insurance_fund(), 50_000_000_000i128);
+    assert_eq!(client.get_bad_debt(), 0);
+    assert_eq!(token_client.balance(&keeper), 0);
+
+    // The vault collects the full 15,000 XLM loss
+    assert_eq!(
+        client.get_vault().balance,
+        10_000_000_000_000i128 + 150_000_000_000
+    );
+}
+
+#[test]
+fn test_auto_deleverage() {
+    let env = Env::default();
+    let (client, user, reflector_id) = setup(&env);
+    let token_client = TokenClient::new(&env, &client.get_collateral_token());
+    let keeper = <soroban_sdk::Address as TestAddress>::generate(&env);
+
+    let btc_asset = Asset::Other(Symbol::new(&env, "BTC"));
+    let long_id = client.open_position(&user, &btc_asset, &1_000_000i128, &5, &100_000_000_000i128);
+    let short_id =
+        client.open_position(&user, &btc_asset, &-1_000_000i128, &5, &100_000_000_000i128);
+
+    // Nothing to deleverage yet
+    let result = client.try_auto_deleverage(&short_id);
+    assert_eq!(result, Err(Ok(Error::NoBadDebt)));
+
+    // With an empty insurance fund the long's 5,000 XLM shortfall becomes bad debt
+    ReflectorMockClient::new(&env, &reflector_id)
+        .set_price(&btc_asset, &3_500_000_000_000_000_000i128);
+    client.liquidate_position(&keeper, &long_id);
+    assert_eq!(client.get_bad_debt(), 50_000_000_000i128);
+
+    // The profitable short is closed with its 15,000 XLM profit haircut by the bad debt
+    assert_eq!(client.auto_deleverage(&short_id), 50_000_000_000i128);
+    assert_eq!(client.get_bad_debt(), 0);
+    assert_eq!(token_client.balance(&user), 1_000_000_000_000i128);
+
+    // The vault is made whole again
+    assert_eq!(client.get_vault().balance, 10_000_000_000_000i128);
+}
+
+#[test]
+fn test_liquidity_vault() {
+    let env = Env::default();
+    let (client, user, _) = setup(&env);
+    let token = client.get_collateral_token();
+    let token_client = TokenClient::new(&env, &token);
+
+    let provider = <soroban_sdk::Address as TestAddress>::generate(&env);
+    StellarAssetClient::new(&env, &token).mint(&provider, &1_000_000_000_000i128);
+
+    // Shares are issued at the current share price (1:1 while the vault has no PnL)
+    assert_eq!(
+        client.deposit_liquidity(&provider, &1_000_000_000_000i128),
+        1_000_000_000_000i128
+    );
+    assert_eq!(client.get_lp_shares(&provider), 1_000_000_000_000i128);
+    assert_eq!(
+        client.get_vault(),
+        Vault {
+            balance: 11_000_000_000_000i128,
+            total_shares: 11_000_000_000_000i128,
+        }
+    );
+
+    // A 0.1 BTC long leaves $5,000 of net exposure for the vault
+    let btc_asset = Asset::Other(Symbol::new(&env, "BTC"));
+    client.open_position(&user, &btc_asset, &1_000_000i128, &5, &100_000_000_000i128);
+
+    client.withdraw_liquidity(&provider, &400_000_000_000i128);
+    assert_eq!(token_client.balance(&provider), 400_000_000_000i128);
+    assert_eq!(client.get_lp_shares(&provider), 600_000_000_000i128);
+
+    let result = client.try_withdraw_liquidity(&provider, &700_000_000_000i128);
+    assert_eq!(result, Err(Ok(Error::InvalidAmount)));
+}
+
+#[test]
+fn test_vault_share_price() {
+    let env = Env::default();
+    let (client, user, reflector_id) = setup_without_liquidity(&env);
+    let token = client.get_collateral_token();
+    let reflector_client = ReflectorMockClient::new(&env, &reflector_id);
+    let btc_asset = Asset::Other(Symbol::new(&env, "BTC"));
+    let first = <soroban_sdk::Address as TestAddress>::generate(&env);
+    let second = <soroban_sdk::Address as TestAddress>::generate(&env);
+    StellarAssetClient::new(&env, &token).mint(&first, &20_000_000_000_000i128);
+    StellarAssetClient::new(&env, &token).mint(&second, &20_000_000_000_000i128);
+
+    // Close fees go to the vault
+    client.set_fee_config(&FeeConfig {
+        treasury_share: 0,
+        insurance_share: 0,
+        vault_share: 10_000,
+        tiers: Vec::new(&env),
+    });
+    let mut market = btc_market(&env, &reflector_id);
+    market.close_fee = 10; // 0.1%
+    client.update_market(&market);
+
+    // With a hedged long and short the vault has no exposure, so its only provider can leave
+    client.deposit_liquidity(&first, &10_000_000_000_000i128);
+    let long_id = client.open_position(&user, &btc_asset, &1_000_000, &5, &100_000_000_000i128);
+    let short_id = client.open_position(&user, &btc_asset, &-1_000_000, &5, &100_000_000_000i128);
+    client.withdraw_liquidity(&first, &10_000_000_000_000i128);
+
+    // The $10 of close fees collected afterwards belong to no provider
+    client.close_position(&user, &long_id);
+    client.close_position(&user, &short_id);
+    assert_eq!(
+        client.get_vault(),
+        Vault {
+            balance: 1_000_000_000,
+            total_shares: 0,
+        }
+    );
+
+    // They go to the insurance fund rather than the next depositor
+    assert_eq!(
+        client.deposit_liquidity(&first, &10_000_000_000_000i128),
+        10_000_000_000_000
+    );
+    assert_eq!(client.get_insurance_fund(), 1_000_000_000);
+    assert_eq!(client.get_vault().balance, 10_000_000_000_000);
+
+    // A 0.1 BTC long up $500 is owed 5,000 XLM by the vault, which lowers the share price
+    client.open_position(&user, &btc_asset, &1_000_000, &5, &100_000_000_000i128);
+    reflector_client.set_price(&btc_asset, &5_500_000_000_000_000_000i128);
+    assert_eq!(client.get_vault_equity(), 9_950_000_000_000);
+    assert_eq!(
+        client.deposit_liquidity(&second, &9_950_000_000_000i128),
+        10_000_000_000_000
+    );
+
+    // Withdrawals are priced the same way
+    assert_eq!(
+        client.withdraw_liquidity(&first, &10_000_000_000_000i128),
+        9_950_000_000_000
+    );
+}
+
+#[test]
+fn test_vault_equity_with_unpriced_market() {
+    let env = Env::default();
+    let (client, user, reflector_id) = setup_without_liquidity(&env);
+    let token = client.get_collateral_token();
+    let reflector_client = ReflectorMockClient::new(&env, &reflector_id);
+    let btc_asset = Asset::Other(Symbol::new(&env, "BTC"));
+    let first = <soroban_sdk::Address as TestAddress>::generate(&env);
+    let second = <soroban_sdk::Address as TestAddress>::generate(&env);
+    StellarAssetClient::new(&env, &token).mint(&first, &20_000_000_000_000i128);
+    StellarAssetClient::new(&env, &token).mint(&second, &20_000_000_000_000i128);
+    client.deposit_liquidity(&first, &10_000_000_000_000i128);
+
+    // 0.1 BTC bought at $50,000 and 0.1 BTC at $55,000 are up $500 at the last trade
+    client.open_position(&user, &btc_asset, &1_000_000, &5, &100_000_000_000i128);
+    reflector_client.set_price(&btc_asset, &5_500_000_000_000_000_000i128);
+    client.open_position(&user, &btc_asset, &1_000_000, &5, &110_000_000_000i128);
+
+    // Without a BTC price the vault can not be valued while the market trades
+    reflector_client.set_unavailable(&btc_asset);
+    assert_eq!(client.try_get_vault_equity(), Err(Ok(Error::OracleError)));
+
+    // Once it is paused, its positions are valued at the last trade, owing 5,000 XLM
+    client.pause_market(&btc_asset);
+    assert_eq!(client.get_vault_equity(), 9_950_000_000_000);
+    assert_eq!(
+        client.deposit_liquidity(&second, &9_950_000_000_000i128),
+        10_000_000_000_000
+    );
+    assert_eq!(
+        client.withdraw_liquidity(&first, &10_000_000_000_000i128),
+        9_950_000_000_000
+    );
+}
+
+#[test]
+fn test_vault_utilization_limit() {
+    let env = Env::default();
+    let (client, user, _) = setup(&env);
+
+    // 2 BTC ($100,000) of net exposure exceeds 80% of the $100,000 vault
+    let btc_asset = Asset::Other(Symbol::new(&env, "BTC"));
+    let result = client.try_open_position(
+        &user,
+        &btc_asset,
+        &20_000_000i128,
+        &10,
+        &1_000_000_000_000i128,
+    );
+    assert_eq!(result, Err(Ok(Error::InsufficientLiquidity)));
+
+    // Raising the limit lets the trade through
+    client.set_max_utilization(&10_000i128);
+    client.open_position(
+        &user,
+        &btc_asset,
+        &20_000_000i128,
+        &10,
+        &1_000_000_000_000i128,
+    );
+    assert_eq!(client.get_max_utilization(), 10_000i128);
+}
+
+#[test]
+fn test_trading_fees() {
+    let env = Env::default();
+    let (client, user, reflector_id) = setup(&env);
+    let token_client = TokenClient::new(&env, &client.get_collateral_token());
+    let treasury = client.get_treasury();
+
+    // 0.1% to open and close, split 50/20/30 between treasury, insurance fund and vault
+    let mut market = btc_market(&env, &reflector_id);
+    market.open_fee = 10;
+    market.close_fee = 10;
+    client.update_market(&market);
+    let fee_config = FeeConfig {
+        treasury_share: 5_000,
+        insurance_share: 2_000,
+        vault_share: 3_000,
+        tiers: Vec::new(&env),
+    };
+    client.set_fee_config(&fee_config);
+    assert_eq!(client.get_fee_config(), fee_config);
+
+    // Collateral net of fees must still cover the initial margin
+    let btc_asset = Asset::Other(Symbol::new(&env, "BTC"));
+    let result =
+        client.try_open_position(&user, &btc_asset, &1_000_000i128, &5, &100_000_000_000i128);
+    assert_eq!(result, Err(Ok(Error::InsufficientCollateral)));
+
+    // The fee on $5,000 of notional is $5 (50 XLM), taken from the collateral
+    let position_id =
+        client.open_position(&user, &btc_asset, &1_000_000i128, &5, &110_000_000_000i128);
+    assert_eq!(
+        client.get_position(&position_id).collateral,
+        110_000_000_000i128 - 500_000_000
+    );
+    assert_eq!(token_client.balance(&treasury), 250_000_000i128);
+    assert_eq!(client.get_insurance_fund(), 100_000_000i128);
+
+    client.close_position(&user, &position_id);
+    assert_eq!(
+        token_client.balance(&user),
+        1_000_000_000_000i128 - 1_000_000_000
+    );
+    assert_eq!(token_client.balance(&treasury), 500_000_000i128);
+    assert_eq!(client.get_insurance_fund(), 200_000_000i128);
+    assert_eq!(
+        client.get_vault().balance,
+        10_000_000_000_000i128 + 300_000_000
+    );
+
+    // Shares must add up to 100%
+    let result = client.try_set_fee_config(&FeeConfig {
+        treasury_share: 5_000,
+        insurance_share: 5_000,
+        vault_share: 1,
+        tiers: Vec::new(&env),
+    });
+    assert_eq!(result, Err(Ok(Error::InvalidAmount)));
+}
+
+#[test]
+fn test_fee_tiers() {
+    let env = Env::default();
+    let (client, user, reflector_id) = setup(&env);
+    let btc_asset = Asset::Other(Symbol::new(&env, "BTC"));
+
+    // 0.1% to open and close, halved once a user has traded $10,000
+    let mut market = btc_market(&env, &reflector_id);
+    market.open_fee = 10;
+    market.close_fee = 10;
+    client.update_market(&market);
+    let mut tiers = Vec::new(&env);
+    tiers.push_back(FeeTier {
+        min_volume: 100_000_000_000,
+        discount: 5_000,
+    });
+    client.set_fee_config(&FeeConfig {
+        treasury_share: 10_000,
+        insurance_share: 0,
+        vault_share: 0,
+        tiers: tiers.clone(),
+    });
+
+    // The first $5,000 is opened and closed at the full fee of 50 XLM
+    let position_id =
+        client.open_position(&user, &btc_asset, &1_000_000i128, &5, &110_000_000_000i128);
+    assert_eq!(
+        client.get_position(&position_id).collateral,
+        110_000_000_000i128 - 500_000_000
+    );
+    assert_eq!(client.get_trading_volume(&user), 50_000_000_000i128);
+    client.close_position(&user, &position_id);
+    assert_eq!(client.get_trading_volume(&user), 100_000_000_000i128);
+
+    // Having reached the tier, the next open pays half
+    let position_id =
+        client.open_position(&user, &btc_asset, &1_000_000i128, &5, &110_000_000_000i128);
+    assert_eq!(
+        client.get_position(&position_id).collateral,
+        110_000_000_000i128 - 250_000_000
+    );
+
+    // Tiers must be sorted by volume, with discounts of at most 100%
+    tiers.push_back(FeeTier {
+        min_volume: 100_000_000_000,
+        discount: 7_500,
+    });
+    let mut config = client.get_fee_config();
+    config.tiers = tiers;
+    assert_eq!(
+        client.try_set_fee_config(&config),
+        Err(Ok(Error::InvalidAmount))
+    );
+    let mut tiers = Vec::new(&env);
+    tiers.push_back(FeeTier {
+        min_volume: 100_000_000_000,
+        discount: 10_001,
+    });
+    config.tiers = tiers;
+    assert_eq!(
+        client.try_set_fee_config(&config),
+        Err(Ok(Error::InvalidAmount))
+    );
+}
+
+// Topics and decoded body of the last event published by the contract
+fn last_event<T: TryFromVal<Env, Val>>(env: &Env, contract_id: &Address) -> (Vec<Val>, T) {
+    let (address, topics, data) = env.events().all().last().unwrap();
+    assert_eq!(&address, contract_id);
+    (topics, T::try_from_val(env, &data).unwrap())
+}
+
+#[test]
+fn test_position_events() {
+    let env = Env::default();
+    let (client, user, reflector_id) = setup(&env);
+
+    let btc_asset = Asset::Other(Symbol::new(&env, "BTC"));
+    let position_id =
+        client.open_position(&user, &btc_asset, &1_000_000i128, &5, &100_000_000_000i128);
+    let (topics, event): (_, PositionOpenedEvent) = last_event(&env, &client.address);
+    assert_eq!(
+        topics,
+        (symbol_short!("opened"), user.clone()).into_val(&env)
+    );
+    assert_eq!(
+        event,
+        PositionOpenedEvent {
+            position_id,
+            asset: btc_asset.clone(),
+            size: 1_000_000,
+            leverage: 5,
+            collateral: 100_000_000_000,
+            entry_price: 5_000_000_000_000_000_000,
+            fee: 0,
+        }
+    );
+
+    // Realized PnL is $500 and the payout is collateral plus 5,000 XLM
+    ReflectorMockClient::new(&env, &reflector_id)
+        .set_price(&btc_asset, &5_500_000_000_000_000_000i128);
+    client.close_position(&user, &position_id);
+    let (topics, event): (_, PositionClosedEvent) = last_event(&env, &client.address);
+    assert_eq!(
+        topics,
+        (symbol_short!("closed"), user.clone()).into_val(&env)
+    );
+    assert_eq!(
+        event,
+        PositionClosedEvent {
+            position_id,
+            asset: btc_asset,
+            exit_price: 5_500_000_000_000_000_000,
+            realized_pnl: 5_000_000_000,
+            funding: 0,
+            fee: 0,
+            payout: 150_000_000_000,
+        }
+    );
+}
+
+#[test]
+fn test_admin_setters_reject_non_admin() {
+    let env = Env::default();
+    let (client, _, reflector_id) = setup(&env);
+    let attacker = <soroban_sdk::Address as TestAddress>::generate(&env);
+    let malicious_oracle = <soroban_sdk::Address as TestAddress>::generate(&env);
+
+    // Only the attacker signs, so the admin check fails
+    env.mock_auths(&[MockAuth {
+        address: &attacker,
+        invoke: &MockAuthInvoke {
+            contract: &client.address,
+            fn_name: "set_oracle",
+            args: (malicious_oracle.clone(),).into_val(&env),
+            sub_invokes: &[],
+        },
+    }]);
+    assert!(client.try_set_oracle(&malicious_oracle).is_err());
+
+    let mut market = btc_market(&env, &malicious_oracle);
+    market.max_leverage = 2;
+    env.mock_auths(&[MockAuth {
+        address: &attacker,
+        invoke: &MockAuthInvoke {
+            contract: &client.address,
+            fn_name: "update_market",
+            args: (market.clone(),).into_val(&env),
+            sub_invokes: &[],
+        },
+    }]);
+    assert!(client.try_update_market(&market).is_err());
+
+    env.mock_auths(&[MockAuth {
+        address: &attacker,
+        invoke: &MockAuthInvoke {
+            contract: &client.address,
+            fn_name: "transfer_admin",
+            args: (attacker.clone(),).into_val(&env),
+            sub_invokes: &[],
+        },
+    }]);
+    assert!(client.try_transfer_admin(&attacker).is_err());
+
+    // Nothing changed
+    assert_eq!(
+        client.get_market(&market.asset),
+        btc_market(&env, &reflector_id)
+    );
+    assert_eq!(client.get_pending_admin(), None);
+}
+
+#[test]
+fn test_transfer_admin() {
+    let env = Env::default();
+    let (client, _, _) = setup(&env);
+    let new_admin = <soroban_sdk::Address as TestAddress>::generate(&env);
+    let other = <soroban_sdk::Address as TestAddress>::generate(&env);
+
+    client.transfer_admin(&new_admin);
+    assert_eq!(client.get_pending_admin(), Some(new_admin.clone()));
+
+    // Only the proposed admin can accept
+    let result = client.try_accept_admin(&other);
+    assert_eq!(result, Err(Ok(Error::Unauthorized)));
+
+    client.accept_admin(&new_admin);
+    assert_eq!(client.get_admin(), new_admin);
+    assert_eq!(client.get_pending_admin(), None);
+
+    // Admin calls now require the new admin's signature
+    client.set_max_utilization(&5_000i128);
+    assert_eq!(env.auths()[0].0, new_admin);
+}
+
+#[test]
+fn test_bump_positions() {
+    let env = Env::default();
+    let (client, user, _) = setup(&env);
+    let btc_asset = Asset::Other(Symbol::new(&env, "BTC"));
+    let provider = <soroban_sdk::Address as TestAddress>::generate(&env);
+    StellarAssetClient::new(&env, &client.get_collateral_token())
+        .mint(&provider, &100_000_000_000i128);
+
+    let open_id = client.open_position(&user, &btc_asset, &1_000_000, &5, &100_000_000_000i128);
+    let closed_id = client.open_position(&user, &btc_asset, &1_000_000, &5, &100_000_000_000i128);
+    client.close_position(&user, &closed_id);
+    let order_id = client.place_limit_order(
+        &user,
+        &btc_asset,
+        &1_000_000,
+        &5,
+        &100_000_000_000i128,
+        &4_800_000_000_000_000_000i128,
+        &(env.ledger().timestamp() + 86_400),
+    );
+    client.deposit_liquidity(&user, &100_000_000_000i128);
+    client.deposit_liquidity(&provider, &100_000_000_000i128);
+
+    let ttl = |key: DataKey| {
+        env.as_contract(&client.address, || env.storage().persistent().get_ttl(&key))
+    };
+    let user_keys = [
+        DataKey::UserPositions(user.clone()),
+        DataKey::UserOrders(user.clone()),
+        DataKey::Order(order_id),
+        DataKey::LpShares(user.clone()),
+        DataKey::TradingVolume(user.clone()),
+    ];
+    assert_eq!(ttl(DataKey::Position(open_id)), POSITION_BUMP_AMOUNT);
+
+    // Let most of the TTL lapse
+    env.ledger()
+        .with_mut(|ledger| ledger.sequence_number += POSITION_BUMP_AMOUNT - 100);
+    assert_eq!(ttl(DataKey::Position(open_id)), 100);
+
+    // Only the open position is extended, along with everything its owner has stored; unknown
+    // IDs are ignored
+    let bumped = client.bump_positions(&soroban_sdk::vec![&env, open_id, closed_id, 99]);
+    assert_eq!(bumped, 1);
+    assert_eq!(ttl(DataKey::Position(open_id)), POSITION_BUMP_AMOUNT);
+    assert_eq!(ttl(DataKey::Position(closed_id)), 100);
+    for key in user_keys {
+        assert_eq!(ttl(key), POSITION_BUMP_AMOUNT);
+    }
+    assert_eq!(ttl(DataKey::LpShares(provider.clone())), 100);
+
+    // Users without open positions, such as liquidity providers, are extended by address
+    client.bump_users(&soroban_sdk::vec![&env, provider.clone()]);
+    assert_eq!(ttl(DataKey::LpShares(provider)), POSITION_BUMP_AMOUNT);
+}
+
+#[test]
+fn test_add_and_remove_collateral() {
+    let env = Env::default();
+    let (client, user, _) = setup(&env);
+    let token_client = TokenClient::new(&env, &client.get_collateral_token());
+    let btc_asset = Asset::Other(Symbol::new(&env, "BTC"));
+
+    // $5,000 notional against $1,000 of collateral
+    let position_id = client.open_position(&user, &btc_asset, &1_000_000, &5, &100_000_000_000i128);
+
+    // Adding $1,000 brings leverage down to 2.5x, rounded up
+    client.add_collateral(&user, &position_id, &100_000_000_000i128);
+    let position = client.get_position(&position_id);
+    assert_eq!(position.collateral, 200_000_000_000);
+    assert_eq!(position.leverage, 3);
+    assert_eq!(token_client.balance(&user), 800_000_000_000);
+
+    // Withdraw down to the initial margin at the market's 10x maximum leverage ($500)
+    client.remove_collateral(&user, &position_id, &150_000_000_000i128);
+    let (_, event): (_, CollateralChangedEvent) = last_event(&env, &client.address);
+    assert_eq!(event.amount, -150_000_000_000);
+    assert_eq!(event.collateral, 50_000_000_000);
+    assert_eq!(event.leverage, 10);
+    assert_eq!(client.get_position(&position_id).collateral, 50_000_000_000);
+    assert_eq!(token_client.balance(&user), 950_000_000_000);
+
+    // Anything more would leave the position below initial margin
+    let result = client.try_remove_collateral(&user, &position_id, &1);
+    assert_eq!(result, Err(Ok(Error::InsufficientCollateral)));
+
+    // Only the owner can change the margin of a position
+    let other = <soroban_sdk::Address as TestAddress>::generate(&env);
+    let result = client.try_add_collateral(&other, &position_id, &1);
+    assert_eq!(result, Err(Ok(Error::Unauthorized)));
+
+    let result = client.try_add_collateral(&user, &position_id, &0);
+    assert_eq!(result, Err(Ok(Error::InvalidAmount)));
+}
+
+#[test]
+fn test_add_collateral_without_price() {
+    let env = Env::default();
+    let (client, user, reflector_id) = setup(&env);
+    let reflector_client = ReflectorMockClient::new(&env, &reflector_id);
+    let btc_asset = Asset::Other(Symbol::new(&env, "BTC"));
+    let position_id = client.open_position(&user, &btc_asset, &1_000_000, &5, &100_000_000_000i128);
+
+    // With a stale oracle the position can not be closed, but margin can still be added
+    reflector_client.set_timestamp(&0);
+    env.ledger().with_mut(|li| li.timestamp = 601);
+    let result = client.try_close_position(&user, &position_id);
+    assert_eq!(result, Err(Ok(Error::PriceStale)));
+    client.add_collateral(&user, &position_id, &100_000_000_000i128);
+
+    // Doubling the collateral halves the 5x leverage, rounded up
+    let position = client.get_position(&position_id);
+    assert_eq!(position.collateral, 200_000_000_000);
+    assert_eq!(position.leverage, 3);
+}
+
+#[test]
+fn test_decrease_position() {
+    let env = Env::default();
+    let (client, user, reflector_id) = setup(&env);
+    let token_client = TokenClient::new(&env, &client.get_collateral_token());
+    let btc_asset = Asset::Other(Symbol::new(&env, "BTC"));
+
+    // 0.1 BTC long at $50,000 with $1,000 of collateral
+    let position_id = client.open_position(&user, &btc_asset, &1_000_000, &5, &100_000_000_000i128);
+
+    // BTC rises 10%, then half the position is closed
+    ReflectorMockClient::new(&env, &reflector_id)
+        .set_price(&btc_asset, &5_500_000_000_000_000_000i128);
+    client.decrease_position(&user, &position_id, &500_000);
+
+    // Half the collateral ($500) is released along with $250 of profit
+    assert_eq!(token_client.balance(&user), 975_000_000_000);
+    let position = client.get_position(&position_id);
+    assert!(position.is_open);
+    assert_eq!(position.size, 500_000);
+    assert_eq!(position.collateral, 50_000_000_000);
+    assert_eq!(position.entry_price, 5_000_000_000_000_000_000);
+    assert_eq!(position.leverage, 6); // $2,750 notional on $500
+    assert_eq!(client.get_open_interest(&btc_asset).long_size, 500_000);
+
+    // The whole position can only be closed with close_position
+    let result = client.try_decrease_position(&user, &position_id, &500_000);
+    assert_eq!(result, Err(Ok(Error::InvalidAmount)));
+}
+
+#[test]
+fn test_increase_position() {
+    let env = Env::default();
+    let (client, user, reflector_id) = setup(&env);
+    let token_client = TokenClient::new(&env, &client.get_collateral_token());
+    let btc_asset = Asset::Other(Symbol::new(&env, "BTC"));
+
+    let position_id = client.open_position(&user, &btc_asset, &1_000_000, &5, &100_000_000_000i128);
+
+    // Add another 0.1 BTC at $60,000 with $1,000 more collateral
+    ReflectorMockClient::new(&env, &reflector_id)
+        .set_price(&btc_asset, &6_000_000_000_000_000_000i128);
+    client.increase_position(&user, &position_id, &1_000_000, &100_000_000_000i128);
+
+    let position = client.get_position(&position_id);
+    assert_eq!(position.size, 2_000_000);
+    assert_eq!(position.collateral, 200_000_000_000);
+    assert_eq!(position.entry_price, 5_500_000_000_000_000_000); // Size-weighted average
+    assert_eq!(position.leverage, 6); // $12,000 notional on $2,000
+    assert_eq!(token_client.balance(&user), 800_000_000_000);
+    assert_eq!(
+        client.get_open_interest(&btc_asset),
+        OpenInterest {
+            long_size: 2_000_000,
+            short_size: 0,
+            long_notional: 110_000_000_000, // 0.2 BTC at the $55,000 average entry
+            short_notional: 0,
+        }
+    );
+
+    // Without enough collateral for the 10x initial margin the increase is refused
+    let result = client.try_increase_position(&user, &position_id, &10_000_000, &0);
+    assert_eq!(result, Err(Ok(Error::InsufficientCollateral)));
+
+    // Closing at the same price realizes the profit on the averaged entry
+    client.close_position(&user, &position_id);
+    assert_eq!(token_client.balance(&user), 1_100_000_000_000); // $1,000 profit
+}
+
+#[test]
+fn test_one_way_mode() {
+    let env = Env::default();
+    let (client, user, reflector_id) = setup(&env);
+    let token_client = TokenClient::new(&env, &client.get_collateral_token());
+    let btc_asset = Asset::Other(Symbol::new(&env, "BTC"));
+
+    let mut market = btc_market(&env, &reflector_id);
+    market.position_mode = PositionMode::OneWay;
+    client.update_market(&market);
+
+    // A second long is added to the first
+    let position_id = client.open_position(&user, &btc_asset, &1_000_000, &5, &100_000_000_000i128);
+    let result = client.open_position(&user, &btc_asset, &1_000_000, &5, &100_000_000_000i128);
+    assert_eq!(result, position_id);
+    let position = client.get_position(&position_id);
+    assert_eq!(position.size, 2_000_000);
+    assert_eq!(position.collateral, 200_000_000_000);
+
+    // A smaller short reduces it, releasing a proportional share of the collateral
+    let result = client.open_position(&user, &btc_asset, &-500_000, &5, &0);
+    assert_eq!(result, position_id);
+    assert_eq!(client.get_position(&position_id).size, 1_500_000);
+    assert_eq!(token_client.balance(&user), 850_000_000_000);
+
+    // A larger short closes it and opens the remainder as a new short
+    let flipped_id = client.open_position(&user, &btc_asset, &-2_500_000, &5, &100_000_000_000i128);
+    assert_ne!(flipped_id, position_id);
+    assert!(!client.get_position(&position_id).is_open);
+    assert_eq!(
+        client.get_net_position(&user, &btc_asset).unwrap().size,
+        -1_000_000
+    );
+    assert_eq!(
+        client.get_user_positions(&user),
+        soroban_sdk::vec![&env, flipped_id]
+    );
+    assert_eq!(token_client.balance(&user), 900_000_000_000);
+    assert_eq!(
+        client.get_open_interest(&btc_asset),
+        OpenInterest {
+            long_size: 0,
+            short_size: 1_000_000,
+            long_notional: 0,
+            short_notional: 50_000_000_000,
+        }
+    );
+
+    // Closing clears the net position
+    client.close_position(&user, &flipped_id);
+    assert_eq!(client.get_net_position(&user, &btc_asset), None);
+}
+
+#[test]
+fn test_limit_order() {
+    let env = Env::default();
+    let (client, user, reflector_id) = setup(&env);
+    let token_client = TokenClient::new(&env, &client.get_collateral_token());
+    let reflector_client = ReflectorMockClient::new(&env, &reflector_id);
+    let keeper = <soroban_sdk::Address as TestAddress>::generate(&env);
+    let btc_asset = Asset::Other(Symbol::new(&env, "BTC"));
+    let execution_fee = client.get_execution_fee();
+
+    // Buy 0.1 BTC if it drops to $48,000
+    let expiry = env.ledger().timestamp() + 86_400;
+    let order_id = client.place_limit_order(
+        &user,
+        &btc_asset,
+        &1_000_000,
+        &5,
+        &100_000_000_000i128,
+        &4_800_000_000_000_000_000i128,
+        &expiry,
+    );
+    assert_eq!(
+        client.get_user_orders(&user),
+        soroban_sdk::vec![&env, order_id]
+    );
+    assert_eq!(token_client.balance(&user), 900_000_000_000 - execution_fee);
+
+    // Not filled at $50,000
+    let result = client.try_execute_order(&keeper, &order_id);
+    assert_eq!(result, Err(Ok(Error::PriceNotReached)));
+
+    reflector_client.set_price(&btc_asset, &4_750_000_000_000_000_000i128);
+    let position_id = client.execute_order(&keeper, &order_id);
+    let (_, event): (_, OrderExecutedEvent) = last_event(&env, &client.address);
+    assert_eq!(event.position_id, position_id);
+    assert_eq!(event.price, 4_750_000_000_000_000_000);
+
+    // The position opens at the oracle price and the keeper is paid
+    let position = client.get_position(&position_id);
+    assert_eq!(position.user, user);
+    assert_eq!(position.size, 1_000_000);
+    assert_eq!(position.collateral, 100_000_000_000);
+    assert_eq!(position.entry_price, 4_750_000_000_000_000_000);
+    assert_eq!(token_client.balance(&keeper), execution_fee);
+    assert_eq!(client.get_user_orders(&user).len(), 0);
+    assert_eq!(
+        client.try_get_order(&order_id),
+        Err(Ok(Error::OrderNotFound))
+    );
+}
+
+#[test]
+fn test_limit_order_flips_one_way_position() {
+    let env = Env::default();
+    let (client, user, reflector_id) = setup(&env);
+    let token_client = TokenClient::new(&env, &client.get_collateral_token());
+    let reflector_client = ReflectorMockClient::new(&env, &reflector_id);
+    let keeper = <soroban_sdk::Address as TestAddress>::generate(&env);
+    let btc_asset = Asset::Other(Symbol::new(&env, "BTC"));
+    let execution_fee = client.get_execution_fee();
+
+    let mut market = btc_market(&env, &reflector_id);
+    market.position_mode = PositionMode::OneWay;
+    client.update_market(&market);
+    let position_id = client.open_position(&user, &btc_asset, &1_000_000, &5, &100_000_000_000i128);
+
+    // Sell 0.2 BTC at $50,000 or more: closes the 0.1 BTC long and opens a 0.1 BTC short
+    let order_id = client.place_limit_order(
+        &user,
+        &btc_asset,
+        &-2_000_000,
+        &5,
+        &100_000_000_000i128,
+        &5_000_000_000_000_000_000i128,
+        &(env.ledger().timestamp() + 3600),
+    );
+    reflector_client.set_price(&btc_asset, &5_000_000_000_000_000_000i128);
+
+    // Only the keeper signs: the short is backed by the escrowed collateral
+    env.mock_auths(&[MockAuth {
+        address: &keeper,
+        invoke: &MockAuthInvoke {
+            contract: &client.address,
+            fn_name: "execute_order",
+            args: (keeper.clone(), order_id).into_val(&env),
+            sub_invokes: &[],
+        },
+    }]);
+    let flipped_id = client.execute_order(&keeper, &order_id);
+    assert_ne!(flipped_id, position_id);
+    assert!(!client.get_position(&position_id).is_open);
+    let position = client.get_position(&flipped_id);
+    assert_eq!(position.size, -1_000_000);
+    assert_eq!(position.collateral, 100_000_000_000);
+
+    // The closed long's collateral is returned, the order's stays in the short
+    assert_eq!(token_client.balance(&user), 900_000_000_000 - execution_fee);
+    assert_eq!(token_client.balance(&keeper), execution_fee);
+}
+
+#[test]
+fn test_cancel_and_expire_limit_order() {
+    let env = Env::default();
+    let (client, user, _) = setup(&env);
+    let token_client = TokenClient::new(&env, &client.get_collateral_token());
+    let keeper = <soroban_sdk::Address as TestAddress>::generate(&env);
+    let btc_asset = Asset::Other(Symbol::new(&env, "BTC"));
+    let expiry = env.ledger().timestamp() + 3600;
+
+    // Sell 0.1 BTC if it rises to $55,000
+    let place = |expiry: u64| {
+        client.place_limit_order(
+            &user,
+            &btc_asset,
+            &-1_000_000,
+            &5,
+            &100_000_000_000i128,
+            &5_500_000_000_000_000_000i128,
+            &expiry,
+        )
+    };
+
+    // Cancelling refunds the collateral and the execution fee
+    let order_id = place(expiry);
+    let other = <soroban_sdk::Address as TestAddress>::generate(&env);
+    let result = client.try_cancel_order(&other, &order_id);
+    assert_eq!(result, Err(Ok(Error::Unauthorized)));
+    client.cancel_order(&user, &order_id);
+    assert_eq!(token_client.balance(&user), 1_000_000_000_000);
+    let result = client.try_execute_order(&keeper, &order_id);
+    assert_eq!(result, Err(Ok(Error::OrderNotFound)));
+
+    // Expired orders can no longer be executed
+    let order_id = place(expiry);
+    env.ledger()
+        .with_mut(|ledger| ledger.timestamp = expiry + 1);
+    let result = client.try_execute_order(&keeper, &order_id);
+    assert_eq!(result, Err(Ok(Error::OrderExpired)));
+    let result = client.try_place_limit_order(
+        &user,
+        &btc_asset,
+        &-1_000_000,
+        &5,
+        &100_000_000_000i128,
+        &5_500_000_000_000_000_000i128,
+        &expiry,
+    );
+    assert_eq!(result, Err(Ok(Error::OrderExpired)));
+}
+
+#[test]
+fn test_take_profit_and_stop_loss() {
+    let env = Env::default();
+    let (client, user, reflector_id) = setup(&env);
+    let token_client = TokenClient::new(&env, &client.get_collateral_token());
+    let reflector_client = ReflectorMockClient::new(&env, &reflector_id);
+    let keeper = <soroban_sdk::Address as TestAddress>::generate(&env);
+    let btc_asset = Asset::Other(Symbol::new(&env, "BTC"));
+    let execution_fee = client.get_execution_fee();
+
+    let long_id = client.open_position(&user, &btc_asset, &1_000_000, &5, &100_000_000_000i128);
+    let short_id = client.open_position(&user, &btc_asset, &-1_000_000, &5, &100_000_000_000i128);
+
+    // A long's take profit must be above the current price
+    let result = client.try_set_tp_sl(&user, &long_id, &Some(4_900_000_000_000_000_000i128), &None);
+    assert_eq!(result, Err(Ok(Error::InvalidTriggerPrice)));
+
+    client.set_tp_sl(
+        &user,
+        &long_id,
+        &Some(5_500_000_000_000_000_000i128),
+        &Some(4_500_000_000_000_000_000i128),
+    );
+    client.set_tp_sl(
+        &user,
+        &short_id,
+        &None,
+        &Some(5_200_000_000_000_000_000i128),
+    );
+    let position = client.get_position(&long_id);
+    assert_eq!(
+        position.take_profit_price,
+        Some(5_500_000_000_000_000_000i128)
+    );
+
+    let result = client.try_trigger_tp_sl(&keeper, &long_id);
+    assert_eq!(result, Err(Ok(Error::PriceNotReached)));
+
+    // At $53,000 the short's stop loss is hit but not the long's take profit
+    reflector_client.set_price(&btc_asset, &5_300_000_000_000_000_000i128);
+    let result = client.try_trigger_tp_sl(&keeper, &long_id);
+    assert_eq!(result, Err(Ok(Error::PriceNotReached)));
+    client.trigger_tp_sl(&keeper, &short_id);
+    let (_, event): (_, TpSlTriggeredEvent) = last_event(&env, &client.address);
+    assert!(!event.take_profit);
+    assert_eq!(event.keeper_fee, execution_fee);
+
+    // At $56,000 the long takes its $600 profit, less the keeper fee
+    reflector_client.set_price(&btc_asset, &5_600_000_000_000_000_000i128);
+    client.trigger_tp_sl(&keeper, &long_id);
+    let (_, event): (_, TpSlTriggeredEvent) = last_event(&env, &client.address);
+    assert!(event.take_profit);
+    assert!(!client.get_position(&long_id).is_open);
+    assert_eq!(token_client.balance(&keeper), 2 * execution_fee);
+    // $300 loss on the short and $600 profit on the long
+    assert_eq!(
+        token_client.balance(&user),
+        1_000_000_000_000 - 30_000_000_000 + 60_000_000_000 - 2 * execution_fee
+    );
+}
+
+#[test]
+fn test_trailing_stop() {
+    let env = Env::default();
+    let (client, user, reflector_id) = setup(&env);
+    let token_client = TokenClient::new(&env, &client.get_collateral_token());
+    let reflector_client = ReflectorMockClient::new(&env, &reflector_id);
+    let keeper = <soroban_sdk::Address as TestAddress>::generate(&env);
+    let btc_asset = Asset::Other(Symbol::new(&env, "BTC"));
+
+    let long_id = client.open_position(&user, &btc_asset, &1_000_000, &5, &100_000_000_000i128);
+    let short_id = client.open_position(&user, &btc_asset, &-1_000_000, &5, &100_000_000_000i128);
+
+    // The long trails by 5%, the short by $1,000
+    let result = client.try_set_trailing_stop(&user, &long_id, &TrailingDistance::Percent(0));
+    assert_eq!(result, Err(Ok(Error::InvalidAmount)));
+    client.set_trailing_stop(&user, &long_id, &TrailingDistance::Percent(500));
+    client.set_trailing_stop(
+        &user,
+        &short_id,
+        &TrailingDistance::Absolute(100_000_000_000_000_000),
+    );
+
+    // BTC rallies to $60,000, moving the long's high-water mark
+    reflector_client.set_price(&btc_asset, &6_000_000_000_000_000_000i128);
+    assert!(!client.poke_trailing_stop(&keeper, &long_id));
+    assert_eq!(
+        client.get_trailing_stop(&long_id).best_price,
+        6_000_000_000_000_000_000
+    );
+
+    // The short's stop at $51,000 was passed on the way up
+    assert!(client.poke_trailing_stop(&keeper, &short_id));
+    assert_eq!(
+        client.try_get_trailing_stop(&short_id),
+        Err(Ok(Error::OrderNotFound))
+    );
+
+    // A retrace to $57,500 is within 5% of the high, one to $57,000 is not
+    reflector_client.set_price(&btc_asset, &5_750_000_000_000_000_000i128);
+    assert!(!client.poke_trailing_stop(&keeper, &long_id));
+    reflector_client.set_price(&btc_asset, &5_700_000_000_000_000_000i128);
+    assert!(client.poke_trailing_stop(&keeper, &long_id));
+    assert!(!client.get_position(&long_id).is_open);
+
+    // $700 profit on the long less the keeper fee; the short lost its whole collateral, so
+    // nothing was left to pay its keeper fee
+    let execution_fee = client.get_execution_fee();
+    assert_eq!(token_client.balance(&keeper), execution_fee);
+    assert_eq!(
+        token_client.balance(&user),
+        1_000_000_000_000 + 70_000_000_000 - 100_000_000_000 - execution_fee
+    );
+}
+
+#[test]
+fn test_cross_margin_account() {
+    let env = Env::default();
+    let (client, user, _) = setup(&env);
+    let token_client = TokenClient::new(&env, &client.get_collateral_token());
+    let keeper = <soroban_sdk::Address as TestAddress>::generate(&env);
+    let btc_asset = Asset::Other(Symbol::new(&env, "BTC"));
+
+    // 20,000 XLM ($2,000) backs both positions
+    client.deposit_margin(&user, &token_client.address, &200_000_000_000i128);
+    assert_eq!(token_client.balance(&user), 800_000_000_000);
+
+    // $1,000 initial margin for the long and $500 for the short
+    let long_id = client.open_cross_position(&user, &btc_asset, &1_000_000, &5);
+    let short_id = client.open_cross_position(&user, &btc_asset, &-500_000, &5);
+    let account = client.get_margin_account(&user);
+    assert_eq!(account.balance, 200_000_000_000);
+    assert_eq!(
+        account.positions,
+        soroban_sdk::vec![&env, long_id, short_id]
+    );
+    assert_eq!(client.get_account_equity(&user), 20_000_000_000);
+    assert_eq!(client.get_position(&long_id).collateral, 0);
+
+    // A third position would take the account below initial margin
+    let result = client.try_open_cross_position(&user, &btc_asset, &1_000_000, &5);
+    assert_eq!(result, Err(Ok(Error::InsufficientCollateral)));
+
+    // Withdraw down to the $1,500 initial margin
+    client.withdraw_margin(&user, &token_client.address, &50_000_000_000i128);
+    assert_eq!(token_client.balance(&user), 850_000_000_000);
+    let result = client.try_withdraw_margin(&user, &token_client.address, &1);
+    assert_eq!(result, Err(Ok(Error::InsufficientCollateral)));
+
+    // Cross positions share the account margin, so per-position margin calls don't apply
+    let result = client.try_add_collateral(&user, &long_id, &1);
+    assert_eq!(result, Err(Ok(Error::IsolatedOnly)));
+    let result = client.try_liquidate_position(&keeper, &long_id);
+    assert_eq!(result, Err(Ok(Error::IsolatedOnly)));
+
+    // Closing settles back into the account instead of the user's wallet
+    client.close_position(&user, &short_id);
+    let account = client.get_margin_account(&user);
+    assert_eq!(account.balance, 150_000_000_000);
+    assert_eq!(account.positions, soroban_sdk::vec![&env, long_id]);
+    assert_eq!(token_client.balance(&user), 850_000_000_000);
+}
+
+#[test]
+fn test_liquidate_account() {
+    let env = Env::default();
+    let (client, user, reflector_id) = setup(&env);
+    let token_client = TokenClient::new(&env, &client.get_collateral_token());
+    let keeper = <soroban_sdk::Address as TestAddress>::generate(&env);
+    let btc_asset = Asset::Other(Symbol::new(&env, "BTC"));
+
+    // Two 0.05 BTC longs at 10x on a 10,000 XLM ($1,000) account
+    client.deposit_margin(&user, &token_client.address, &100_000_000_000i128);
+    let first_id = client.open_cross_position(&user, &btc_asset, &500_000, &10);
+    let second_id = client.open_cross_position(&user, &btc_asset, &500_000, &10);
+
+    // At $45,000 equity is $500 against a $225 maintenance margin
+    let reflector_client = ReflectorMockClient::new(&env, &reflector_id);
+    reflector_client.set_price(&btc_asset, &4_500_000_000_000_000_000i128);
+    let result = client.try_liquidate_account(&keeper, &user);
+    assert_eq!(result, Err(Ok(Error::MaintenanceMarginNotMet)));
+
+    // At $42,000 equity is $200 against a $210 maintenance margin
+    reflector_client.set_price(&btc_asset, &4_200_000_000_000_000_000i128);
+    let closed = client.liquidate_account(&keeper, &user);
+
+    // Closing one position is enough: $179 of equity left against $105
+    assert_eq!(closed, soroban_sdk::vec![&env, first_id]);
+    assert!(!client.get_position(&first_id).is_open);
+    assert!(client.get_position(&second_id).is_open);
+
+    // Keeper earns 1% of the $2,100 notional, the vault collects the 4,000 XLM loss
+    assert_eq!(token_client.balance(&keeper), 2_100_000_000);
+    let account = client.get_margin_account(&user);
+    assert_eq!(account.balance, 57_900_000_000);
+    assert_eq!(account.positions, soroban_sdk::vec![&env, second_id]);
+    assert_eq!(
+        client.get_vault().balance,
+        10_000_000_000_000i128 + 40_000_000_000
+    );
+}
+
+#[test]
+fn test_multi_collateral() {
+    let env = Env::default();
+    let (client, user, reflector_id) = setup(&env);
+    let token_client = TokenClient::new(&env, &client.get_collateral_token());
+    let keeper = <soroban_sdk::Address as TestAddress>::generate(&env);
+    let btc_asset = Asset::Other(Symbol::new(&env, "BTC"));
+
+    // USDC at $1 backs margin at 90% of its value
+    let issuer = <soroban_sdk::Address as TestAddress>::generate(&env);
+    let usdc = env.register_stellar_asset_contract_v2(issuer).address();
+    let usdc_asset = Asset::Other(Symbol::new(&env, "USDC"));
+    let reflector_client = ReflectorMockClient::new(&env, &reflector_id);
+    reflector_client.set_price(&usdc_asset, &100_000_000_000_000i128);
+    let mut config = CollateralConfig {
+        token: usdc.clone(),
+        asset: usdc_asset,
+        haircut: 10_000,
+        is_active: true,
+    };
+    let result = client.try_set_collateral_config(&config);
+    assert_eq!(result, Err(Ok(Error::InvalidAmount)));
+    config.haircut = 1_000;
+    client.set_collateral_config(&config);
+    assert_eq!(
+        client.get_collateral_tokens(),
+        soroban_sdk::vec![&env, token_client.address.clone(), usdc.clone()]
+    );
+
+    // The settlement token can be neither discounted nor deactivated
+    let mut settlement = xlm_collateral(&env, &token_client.address);
+    settlement.haircut = 1_000;
+    let result = client.try_set_collateral_config(&settlement);
+    assert_eq!(result, Err(Ok(Error::InvalidAmount)));
+    settlement.haircut = 0;
+    settlement.is_active = false;
+    let result = client.try_set_collateral_config(&settlement);
+    assert_eq!(result, Err(Ok(Error::InvalidAmount)));
+
+    // 2,000 USDC counts as $1,800 of equity
+    StellarAssetClient::new(&env, &usdc).mint(&user, &20_000_000_000i128);
+    client.deposit_margin(&user, &usdc, &20_000_000_000i128);
+    assert_eq!(client.get_account_equity(&user), 18_000_000_000);
+
+    // $1,000 of initial margin for 0.1 BTC at 5x leaves room to withdraw at most $800 worth
+    let position_id = client.open_cross_position(&user, &btc_asset, &1_000_000, &5);
+    let result = client.try_withdraw_margin(&user, &usdc, &10_000_000_000i128);
+    assert_eq!(result, Err(Ok(Error::InsufficientCollateral)));
+
+    // At $33,000 equity is $100 against a $165 maintenance margin
+    reflector_client.set_price(&btc_asset, &3_300_000_000_000_000_000i128);
+    StellarAssetClient::new(&env, &token_client.address).mint(&keeper, &200_000_000_000i128);
+    client.liquidate_account(&keeper, &user);
+
+    // The $1,700 loss leaves the account owing $1,733 after the $33 liquidation fee. The keeper
+    // repays it and receives USDC worth that at the haircut value.
+    let (_, event): (_, CollateralSeizedEvent) = last_event(&env, &client.address);
+    assert_eq!(event.amount, 19_255_555_556);
+    assert_eq!(event.repaid, 173_300_000_000);
+    assert!(!client.get_position(&position_id).is_open);
+    assert_eq!(token_client.balance(&keeper), 30_000_000_000);
+    assert_eq!(
+        TokenClient::new(&env, &usdc).balance(&keeper),
+        19_255_555_556
+    );
+
+    let account = client.get_margin_account(&user);
+    assert_eq!(account.balance, 0);
+    assert_eq!(account.collateral.get(usdc.clone()), Some(744_444_444));
+    assert_eq!(
+        client.get_vault().balance,
+        10_000_000_000_000i128 + 170_000_000_000
+    );
+
+    // The rest can be withdrawn
+    client.withdraw_margin(&user, &usdc, &744_444_444i128);
+    assert_eq!(TokenClient::new(&env, &usdc).balance(&user), 744_444_444);
+}
+
+#[test]
+fn test_collateral_shortfall_becomes_bad_debt() {
+    let env = Env::default();
+    let (client, user, reflector_id) = setup(&env);
+    let token_client = TokenClient::new(&env, &client.get_collateral_token());
+    let keeper = <soroban_sdk::Address as TestAddress>::generate(&env);
+    let btc_asset = Asset::Other(Symbol::new(&env, "BTC"));
+    let reflector_client = ReflectorMockClient::new(&env, &reflector_id);
+
+    // 2,000 USDC at $1 and a 10% haircut backs a 0.1 BTC long
+    let issuer = <soroban_sdk::Address as TestAddress>::generate(&env);
+    let usdc = env.register_stellar_asset_contract_v2(issuer).address();
+    let usdc_asset = Asset::Other(Symbol::new(&env, "USDC"));
+    reflector_client.set_price(&usdc_asset, &100_000_000_000_000i128);
+    client.set_collateral_config(&CollateralConfig {
+        token: usdc.clone(),
+        asset: usdc_asset.clone(),
+        haircut: 1_000,
+        is_active: true,
+    });
+    StellarAssetClient::new(&env, &usdc).mint(&user, &20_000_000_000i128);
+    client.deposit_margin(&user, &usdc, &20_000_000_000i128);
+    let position_id = client.open_cross_position(&user, &btc_asset, &1_000_000, &5);
+
+    // Closing at $45,000 leaves the account owing the vault $500 against its USDC
+    reflector_client.set_price(&btc_asset, &4_500_000_000_000_000_000i128);
+    client.close_position(&user, &position_id);
+    assert_eq!(client.get_margin_account(&user).balance, -50_000_000_000);
+
+    // USDC falls to $0.20 before the debt is collected: the keeper repays the $360 it is worth
+    // and the remaining $140 is bad debt, taken back out of the vault
+    reflector_client.set_price(&usdc_asset, &20_000_000_000_000i128);
+    StellarAssetClient::new(&env, &token_client.address).mint(&keeper, &50_000_000_000i128);
+    client.liquidate_account(&keeper, &user);
+
+    let account = client.get_margin_account(&user);
+    assert_eq!(account.balance, 0);
+    assert!(account.collateral.is_empty());
+    assert_eq!(token_client.balance(&keeper), 14_000_000_000);
+    assert_eq!(client.get_bad_debt(), 14_000_000_000);
+    assert_eq!(
+        client.get_vault().balance,
+        10_000_000_000_000i128 + 36_000_000_000
+    );
+    assert_eq!(
+        token_client.balance(&client.address),
+        client.get_vault().balance
+    );
+}
+
+#[test]
+fn test_oracle_price_validation() {
+    let env = Env::default();
+    let (client, user, reflector_id) = setup(&env);
+    let reflector_client = ReflectorMockClient::new(&env, &reflector_id);
+    let btc_asset = Asset::Other(Symbol::new(&env, "BTC"));
+
+    let position_id = client.open_position(&user, &btc_asset, &1_000_000, &5, &100_000_000_000i128);
+
+    // Prices older than two 5 minute oracle periods are stale for every read
+    reflector_client.set_timestamp(&0);
+    env.ledger().with_mut(|li| li.timestamp = 601);
+    let result = client.try_close_position(&user, &position_id);
+    assert_eq!(result, Err(Ok(Error::PriceStale)));
+    reflector_client.set_timestamp(&601);
+
+    // Non-positive prices are never accepted
+    reflector_client.set_price(&btc_asset, &0);
+    let result = client.try_close_position(&user, &position_id);
+    assert_eq!(result, Err(Ok(Error::OracleError)));
+
+    // A 12% move is rejected once deviation is bounded to 10%
+    client.set_oracle_config(&OracleConfig {
+        max_age_periods: 2,
+        max_deviation: 1_000,
+    });
+    reflector_client.set_price(&btc_asset, &5_600_000_000_000_000_000i128);
+    let result = client.try_close_position(&user, &position_id);
+    assert_eq!(result, Err(Ok(Error::PriceDeviation)));
+
+    // Anyone can trip the circuit breaker, which stops new positions
+    assert!(client.trip_circuit_breaker(&btc_asset));
+    assert!(client.is_circuit_breaker_tripped(&btc_asset));
+    assert!(!client.trip_circuit_breaker(&btc_asset));
+    let result = client.try_open_position(&user, &btc_asset, &1_000_000, &5, &100_000_000_000i128);
+    assert_eq!(result, Err(Ok(Error::InvalidAsset)));
+
+    // Once reset, the new price is accepted as the reference
+    client.reset_circuit_breaker(&btc_asset);
+    assert!(!client.is_circuit_breaker_tripped(&btc_asset));
+    client.close_position(&user, &position_id);
+    let (_, event): (_, PositionClosedEvent) = last_event(&env, &client.address);
+    assert_eq!(event.exit_price, 5_600_000_000_000_000_000);
+}
+
+#[test]
+fn test_circuit_breaker_settles_and_keeps_pause() {
+    let env = Env::default();
+    let (client, user, reflector_id) = setup(&env);
+    let reflector_client = ReflectorMockClient::new(&env, &reflector_id);
+    let btc_asset = Asset::Other(Symbol::new(&env, "BTC"));
+
+    let first_id = client.open_position(&user, &btc_asset, &1_000_000, &5, &100_000_000_000i128);
+    let second_id = client.open_position(&user, &btc_asset, &1_000_000, &5, &100_000_000_000i128);
+    client.set_oracle_config(&OracleConfig {
+        max_age_periods: 2,
+        max_deviation: 1_000,
+    });
+    reflector_client.set_price(&btc_asset, &5_600_000_000_000_000_000i128);
+    assert!(client.trip_circuit_breaker(&btc_asset));
+
+    // Positions still settle at the oracle price while the breaker is tripped
+    client.close_position(&user, &first_id);
+    let (_, event): (_, PositionClosedEvent) = last_event(&env, &client.address);
+    assert_eq!(event.exit_price, 5_600_000_000_000_000_000);
+
+    // Updating the market does not clear the breaker
+    client.update_market(&btc_market(&env, &reflector_id));
+    let result = client.try_open_position(&user, &btc_asset, &1_000_000, &5, &100_000_000_000i128);
+    assert_eq!(result, Err(Ok(Error::InvalidAsset)));
+
+    // Resetting the breaker does not resume a market the admin paused
+    client.pause_market(&btc_asset);
+    client.reset_circuit_breaker(&btc_asset);
+    assert!(!client.get_market(&btc_asset).is_active);
+    let result = client.try_open_position(&user, &btc_asset, &1_000_000, &5, &100_000_000_000i128);
+    assert_eq!(result, Err(Ok(Error::InvalidAsset)));
+    client.close_position(&user, &second_id);
+}
+
+#[test]
+fn test_collateral_and_quote_prices_skip_deviation_check() {
+    let env = Env::default();
+    let (client, user, reflector_id) = setup(&env);
+    let reflector_client = ReflectorMockClient::new(&env, &reflector_id);
+    let btc_asset = Asset::Other(Symbol::new(&env, "BTC"));
+    let eth_asset = Asset::Other(Symbol::new(&env, "ETH"));
+    let xlm_asset = Asset::Other(Symbol::new(&env, "XLM"));
+
+    let mut eth_market = btc_market(&env, &reflector_id);
+    eth_market.asset = eth_asset.clone();
+    eth_market.quote_asset = QuoteAsset::Asset(btc_asset.clone());
+    client.add_market(&eth_market);
+    let btc_id = client.open_position(&user, &btc_asset, &1_000_000, &5, &100_000_000_000i128);
+    let eth_id = client.open_position(&user, &eth_asset, &10_000_000, &10, &50_000_000_000i128);
+    client.set_oracle_config(&OracleConfig {
+        max_age_periods: 2,
+        max_deviation: 1_000,
+    });
+
+    // A 20% move in the collateral price has no circuit breaker to reset, so it is not bounded
+    reflector_client.set_price(&xlm_asset, &12_000_000_000_000i128);
+    client.add_collateral(&user, &btc_id, &10_000_000_000i128);
+    client.close_position(&user, &btc_id);
+
+    // Neither is the quote asset: ETH/BTC has not moved when both are up 20%
+    reflector_client.set_price(&eth_asset, &120_000_000_000_000_000i128);
+    reflector_client.set_price(&btc_asset, &6_000_000_000_000_000_000i128);
+    client.close_position(&user, &eth_id);
+}
+
+#[test]
+fn test_mark_price() {
+    let env = Env::default();
+    let (client, user, reflector_id) = setup(&env);
+    let reflector_client = ReflectorMockClient::new(&env, &reflector_id);
+    let keeper = <soroban_sdk::Address as TestAddress>::generate(&env);
+    let btc_asset = Asset::Other(Symbol::new(&env, "BTC"));
+
+    // Mark the market at a 5 record average, with no fallback
+    let mut market = btc_market(&env, &reflector_id);
+    market.mark_price_records = 5;
+    market.mark_price_fallback = MarkPriceFallback::Reject;
+    client.update_market(&market);
+
+    let position_id = client.open_position(&user, &btc_asset, &1_000_000, &5, &100_000_000_000i128);
+
+    // Funding is charged on the $60,000 mark price: $6 for one interval on 0.1 BTC
+    reflector_client.set_twap(&btc_asset, &Some(6_000_000_000_000_000_000i128));
+    env.ledger().with_mut(|li| li.timestamp = 3_600);
+    let funding = client.update_funding(&btc_asset);
+    assert_eq!(funding.cumulative_index, 1_000_000 * 600_000_000_000);
+
+    // A $42,000 tick alone does not liquidate while the average holds at $50,000
+    reflector_client.set_price(&btc_asset, &4_200_000_000_000_000_000i128);
+    reflector_client.set_twap(&btc_asset, &Some(5_000_000_000_000_000_000i128));
+    let result = client.try_liquidate_position(&keeper, &position_id);
+    assert_eq!(result, Err(Ok(Error::MaintenanceMarginNotMet)));
+
+    // Without an average the market rejects liquidations unless it falls back to the spot price
+    reflector_client.set_twap(&btc_asset, &None);
+    let result = client.try_liquidate_position(&keeper, &position_id);
+    assert_eq!(result, Err(Ok(Error::OracleError)));
+
+    market.mark_price_fallback = MarkPriceFallback::Spot;
+    client.update_market(&market);
+    client.liquidate_position(&keeper, &position_id);
+    assert!(!client.get_position(&position_id).is_open);
+}
+
+#[test]
+fn test_multi_oracle() {
+    let env = Env::default();
+    let (client, user, reflector_id) = setup(&env);
+    let btc_asset = Asset::Other(Symbol::new(&env, "BTC"));
+
+    // $50,000 from the default feed, $51,000 from a feed with 8 decimals and $60,000 from a third
+    let second_id = env.register(ReflectorMock, ());
+    let second_client = ReflectorMockClient::new(&env, &second_id);
+    second_client.set_decimals(&8);
+    second_client.set_price(&btc_asset, &5_100_000_000_000i128);
+    let third_id = env.register(ReflectorMock, ());
+    let third_client = ReflectorMockClient::new(&env, &third_id);
+    third_client.set_price(&btc_asset, &6_000_000_000_000_000_000i128);
+
+    let mut market = btc_market(&env, &reflector_id);
+    market.oracle_sources = soroban_sdk::vec![&env, reflector_id, second_id, third_id];
+    market.oracle_quorum = 4;
+    let result = client.try_update_market(&market);
+    assert_eq!(result, Err(Ok(Error::InvalidAmount)));
+    market.oracle_quorum = 2;
+    client.update_market(&market);
+
+    // Positions open at the median
+    let position_id =
+        client.open_position(&user, &btc_asset, &1_000_000, &10, &100_000_000_000i128);
+    assert_eq!(
+        client.get_position(&position_id).entry_price,
+        5_100_000_000_000_000_000
+    );
+
+    // A source without a price is skipped while the others still make quorum
+    third_client.set_unavailable(&btc_asset);
+    let position_id =
+        client.open_position(&user, &btc_asset, &1_000_000, &10, &100_000_000_000i128);
+    assert_eq!(
+        client.get_position(&position_id).entry_price,
+        5_050_000_000_000_000_000
+    );
+
+    // So is a stale source, but a single fresh one is not enough
+    second_client.set_timestamp(&0);
+    env.ledger().with_mut(|li| li.timestamp = 601);
+    let result = client.try_close_position(&user, &position_id);
+    assert_eq!(result, Err(Ok(Error::OracleError)));
+}
+
+#[test]
+fn test_cross_price_market() {
+    let env = Env::default();
+    let (client, user, reflector_id) = setup(&env);
+    let token_client = TokenClient::new(&env, &client.get_collateral_token());
+    let eth_asset = Asset::Other(Symbol::new(&env, "ETH"));
+    let btc_asset = Asset::Other(Symbol::new(&env, "BTC"));
+    let reflector_client = ReflectorMockClient::new(&env, &reflector_id);
+
+    let mut market = btc_market(&env, &reflector_id);
+    market.asset = eth_asset.clone();
+    market.quote_asset = QuoteAsset::Asset(eth_asset.clone());
+    let result = client.try_add_market(&market);
+    assert_eq!(result, Err(Ok(Error::InvalidAsset)));
+
+    // ETH/BTC at $2,500 / $50,000 = 0.05 BTC
+    market.quote_asset = QuoteAsset::Asset(btc_asset.clone());
+    client.add_market(&market);
+    reflector_client.set_price(&eth_asset, &250_000_000_000_000_000i128);
+
+    // 1 ETH long with 5,000 XLM ($500) of collateral; the entry price is in BTC
+    let initial_balance = token_client.balance(&user);
+    let position_id =
+        client.open_position(&user, &eth_asset, &10_000_000, &10, &50_000_000_000i128);
+    assert_eq!(
+        client.get_position(&position_id).entry_price,
+        5_000_000_000_000
+    );
+
+    // ETH and BTC both up 10% in USD: the pair did not move, so neither did the PnL
+    reflector_client.set_price(&eth_asset, &275_000_000_000_000_000i128);
+    reflector_client.set_price(&btc_asset, &5_500_000_000_000_000_000i128);
+    client.close_position(&user, &position_id);
+    assert_eq!(token_client.balance(&user), initial_balance);
+
+    // ETH/BTC up to 0.055: the 0.005 BTC profit is paid at $55,000, i.e. $275 or 2,750 XLM
+    let position_id =
+        client.open_position(&user, &eth_asset, &10_000_000, &10, &50_000_000_000i128);
+    reflector_client.set_price(&eth_asset, &302_500_000_000_000_000i128);
+    client.close_position(&user, &position_id);
+    assert_eq!(
+        token_client.balance(&user),
+        initial_balance + 27_500_000_000
+    );
+}
+
+#[test]
+fn test_oracle_discovery() {
+    let env = Env::default();
+    let (client, user, reflector_id) = setup(&env);
+    let btc_asset = Asset::Other(Symbol::new(&env, "BTC"));
+
+    // The oracle's precision and update period are cached when it is configured
+    assert_eq!(
+        client.get_oracle_source(&reflector_id),
+        OracleSource {
+            decimals: 14,
+            resolution: 300,
+        }
+    );
+
+    // Assets the oracle does not quote can not be listed
+    let mut market = btc_market(&env, &reflector_id);
+    market.asset = Asset::Other(Symbol::new(&env, "DOGE"));
+    let result = client.try_add_market(&market);
+    assert_eq!(result, Err(Ok(Error::InvalidAsset)));
+
+    // An oracle with 8 decimals updating every minute, quoting BTC at $51,000
+    let oracle_id = env.register(ReflectorMock, ());
+    let oracle_client = ReflectorMockClient::new(&env, &oracle_id);
+    oracle_client.set_decimals(&8);
+    oracle_client.set_resolution(&60);
+    oracle_client.set_price(&btc_asset, &5_100_000_000_000i128);
+    oracle_client.set_timestamp(&0);
+    let result = client.try_get_oracle_source(&oracle_id);
+    assert_eq!(result, Err(Ok(Error::OracleError)));
+
+    client.update_market(&btc_market(&env, &oracle_id));
+    assert_eq!(
+        client.get_oracle_source(&oracle_id),
+        OracleSource {
+            decimals: 8,
+            resolution: 60,
+        }
+    );
+
+    // Its prices are rescaled to 14 decimals
+    let position_id =
+        client.open_position(&user, &btc_asset, &1_000_000, &10, &100_000_000_000i128);
+    assert_eq!(
+        client.get_position(&position_id).entry_price,
+        5_100_000_000_000_000_000
+    );
+
+    // And go stale after two of its own periods rather than the default oracle's
+    env.ledger().with_mut(|li| li.timestamp = 121);
+    let result = client.try_close_position(&user, &position_id);
+    assert_eq!(result, Err(Ok(Error::PriceStale)));
 }
@@ -21,6 +21,42 @@ const CONTRACT_ADDRESSES = {
   mainnet: 'YOUR_CONTRACT_ADDRESS_HERE', // placeholder
 };
 
+// Collateral token the contract is initialized with: the native XLM Stellar Asset Contract on
+// testnet, priced as XLM on the oracle
+const COLLATERAL_TOKEN = 'CDLZFC3SYJYDZT7K67VZ75HPJVIEUVNIXF47ZG2FB2RMQQVU2HHGCYSC';
+const COLLATERAL_ASSET = 'XLM';
+
+// Markets are keyed by Reflector's Asset enum: a contract address is a Stellar token
+// (Asset::Stellar), anything else a symbol such as "BTC" (Asset::Other)
+function assetToScVal(asset: string): StellarSdk.xdr.ScVal {
+  const isStellarToken = StellarSdk.StrKey.isValidContract(asset);
+  return StellarSdk.xdr.ScVal.scvVec([
+    StellarSdk.xdr.ScVal.scvSymbol(isStellarToken ? 'Stellar' : 'Other'),
+    StellarSdk.nativeToScVal(asset, { type: isStellarToken ? 'address' : 'symbol' }),
+  ]);
+}
+
+// Encode the settlement token as a CollateralConfig, valued without a haircut. Struct fields are
+// encoded as a map sorted by field name.
+function collateralConfigToScVal(token: string, asset: string): StellarSdk.xdr.ScVal {
+  const field = (name: string, val: StellarSdk.xdr.ScVal) =>
+    new StellarSdk.xdr.ScMapEntry({ key: StellarSdk.xdr.ScVal.scvSymbol(name), val });
+  return StellarSdk.xdr.ScVal.scvMap([
+    field('asset', assetToScVal(asset)),
+    field('haircut', StellarSdk.nativeToScVal(0, { type: 'i128' })),
+    field('is_active', StellarSdk.xdr.ScVal.scvBool(true)),
+    field('token', StellarSdk.nativeToScVal(token, { type: 'address' })),
+  ]);
+}
+
+// Decode an Asset enum returned by the contract (["Other", "BTC"] or ["Stellar", address])
+function assetFromNative(value: unknown): string | null {
+  if (Array.isArray(value) && value.length === 2) {
+    return String(value[1]);
+  }
+  return null;
+}
+
 // Contract function names
 const CONTRACT_FUNCTIONS = {
   INITIALIZE: 'initialize',
@@ -29,7 +65,7 @@ const CONTRACT_FUNCTIONS = {
   LIQUIDATE_POSITION: 'liquidate_position',
   GET_POSITION: 'get_position',
   GET_USER_POSITIONS: 'get_user_positions',
-  GET_MARKET_CONFIG: 'get_market_config',
+  GET_MARKET_CONFIG: 'get_market',
   GET_FUNDING_RATE: 'get_funding_rate',
   GET_ADMIN: 'get_admin',
   SET_ORACLE: 'set_oracle',
@@ -210,10 +246,10 @@ export class ContractService {
       console.log('🔑 Using user address:', publicKey);
 
       // Use the contract interaction helper with correct parameter order
-      // Contract expects: user, asset_symbol, size, leverage, collateral
+      // Contract expects: user, asset, size, leverage, collateral
       const contractArgs = [
         StellarSdk.nativeToScVal(publicKey, { type: 'address' }),
-        StellarSdk.nativeToScVal(asset, { type: 'symbol' }),
+        assetToScVal(asset),
         StellarSdk.nativeToScVal(positionSize, { type: 'i128' }),
         StellarSdk.nativeToScVal(leverage, { type: 'u32' }),
         StellarSdk.nativeToScVal(collateral, { type: 'i128' }),
@@ -424,14 +460,8 @@ export class ContractService {
           if (positionData && typeof positionData === 'object') {
             const pos = positionData as Record<string, unknown>;
             
-            // Convert Reflector asset symbol to string
-            let assetSymbol = 'BTC';
-            if (pos.asset && typeof pos.asset === 'object') {
-              const asset = pos.asset as Record<string, unknown>;
-              if (asset.other && typeof asset.other === 'string') {
-                assetSymbol = asset.other;
-              }
-            }
+            // Convert the Reflector asset to its symbol or token address
+            const assetSymbol = assetFromNative(pos.asset) || 'BTC';
             
             const position: ContractPosition = {
               id: positionId.toString(),
@@ -471,20 +501,21 @@ export class ContractService {
       // Try to get from contract first
       const result = await this.callContractReadOnly(
         CONTRACT_FUNCTIONS.GET_MARKET_CONFIG,
-        [StellarSdk.nativeToScVal(asset, { type: 'symbol' })]
+        [assetToScVal(asset)]
       );
 
       if (result && typeof result === 'object') {
         console.log(`✅ Market config from contract for ${asset}:`, result);
         const config = result as Record<string, unknown>;
+        const oracleSources = config.oracle_sources as string[] | undefined;
         return {
           asset,
-          minCollateral: config.minCollateral?.toString() || '100',
-          maxLeverage: Number(config.maxLeverage) || 10,
-          maintenanceMargin: Number(config.maintenanceMargin) || 500,
-          fundingRateInterval: Number(config.fundingRateInterval) || 28800,
-          oracleAddress: (config.oracleAddress as string) || 'CCYOZJCOPG34LLQQ7N24YXBM7LL62R7ONMZ3G6WZAAYPB5OYKOMJRN63',
-          isActive: Boolean(config.isActive ?? true),
+          minCollateral: config.min_collateral?.toString() || '100',
+          maxLeverage: Number(config.max_leverage) || 10,
+          maintenanceMargin: Number(config.maintenance_margin) || 500,
+          fundingRateInterval: Number(config.funding_rate_interval) || 28800,
+          oracleAddress: oracleSources?.[0] || 'CCYOZJCOPG34LLQQ7N24YXBM7LL62R7ONMZ3G6WZAAYPB5OYKOMJRN63',
+          isActive: Boolean(config.is_active ?? true),
         };
       }
 
@@ -513,7 +544,7 @@ export class ContractService {
       // Try to get from contract first
       const result = await this.callContractReadOnly(
         CONTRACT_FUNCTIONS.GET_FUNDING_RATE,
-        [StellarSdk.nativeToScVal(asset, { type: 'symbol' })]
+        [assetToScVal(asset)]
       );
 
       if (result && typeof result === 'object') {
@@ -555,13 +586,13 @@ export class ContractService {
         return {
           id: position.id?.toString() || positionId,
           user: (position.user as string) || '',
-          asset: (position.asset as string) || 'BTC',
+          asset: assetFromNative(position.asset) || 'BTC',
           size: position.size?.toString() || '0',
           collateral: position.collateral?.toString() || '0',
-          entryPrice: position.entryPrice?.toString() || '0',
+          entryPrice: position.entry_price?.toString() || '0',
           leverage: Number(position.leverage) || 1,
           timestamp: Number(position.timestamp) || Date.now(),
-          isOpen: Boolean(position.isOpen ?? true),
+          isOpen: Boolean(position.is_open ?? true),
         };
       }
 
@@ -616,6 +647,7 @@ export class ContractService {
         [
           StellarSdk.nativeToScVal(admin, { type: 'address' }),
           StellarSdk.nativeToScVal(treasury, { type: 'address' }),
+          collateralConfigToScVal(COLLATERAL_TOKEN, COLLATERAL_ASSET),
           StellarSdk.nativeToScVal(minCollateral, { type: 'i128' }),
           StellarSdk.nativeToScVal(maxLeverage, { type: 'u32' }),
           StellarSdk.nativeToScVal(maintenanceMargin, { type: 'i128' }),